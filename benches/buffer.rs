@@ -0,0 +1,30 @@
+#[allow(dead_code)]
+#[path = "../src/buffer.rs"]
+mod buffer;
+
+use buffer::Buffer;
+use std::time::Instant;
+
+const ITERATIONS: u32 = 10_000;
+
+fn main() {
+    for lines in [10_000, 100_000, 1_000_000] {
+        let contents = "the quick brown fox jumps over the lazy dog\n".repeat(lines);
+        let mut buffer = Buffer::new(None::<&str>, &contents);
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            let cy = buffer.len() / 2;
+            buffer.insert_char(4, cy, 'x').unwrap();
+            buffer.remove_char(4, cy).unwrap();
+            buffer.insert_new_line(cy, 0, false).unwrap();
+            buffer.remove_line(cy).unwrap();
+        }
+
+        println!(
+            "{:>9} lines: {:?} per edit cycle in the middle of the buffer",
+            lines,
+            start.elapsed() / ITERATIONS
+        );
+    }
+}
@@ -1,30 +1,132 @@
 use anyhow::Context;
-use std::path::PathBuf;
+use regex::Regex;
+use ropey::{Rope, RopeBuilder};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Files bigger than this are shown as soon as their first chunk is read and
+/// the rest is streamed in behind the view.
+const STREAM_SIZE: u64 = 8 << 20;
+const CHUNK_SIZE: u64 = 1 << 20;
+/// How long `load_more` may spend appending chunks before the next frame.
+const LOAD_BUDGET: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineEnding {
+    Unix,
+    Dos,
+}
+
+impl LineEnding {
+    pub fn detect(contents: &str) -> Self {
+        let dos = contents.matches("\r\n").count();
+        if dos > contents.matches('\n').count() - dos {
+            LineEnding::Dos
+        } else {
+            LineEnding::Unix
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Unix => "\n",
+            LineEnding::Dos => "\r\n",
+        }
+    }
+}
+
+/// The text and cursor at some point in the buffer's history.
+struct Snapshot {
+    text: Rope,
+    cursor: (usize, usize),
+    modified: bool,
+}
+
+#[derive(Default)]
+struct History {
+    undo: Vec<Snapshot>,
+    redo: Vec<Snapshot>,
+    /// The state before the change in progress, and the version it was at.
+    change_start: Option<(Snapshot, u64)>,
+}
 
 pub struct Buffer {
     pub file: Option<PathBuf>,
-    pub lines: Vec<String>,
     pub is_modified: bool,
+    pub end_of_line: bool,
+    pub line_ending: LineEnding,
+    pub marks: HashMap<char, (usize, usize)>,
+    /// Set when the file wasn't valid UTF-8 and was decoded lossily.
+    pub binary: bool,
+    /// Rejects edits, and saving without `!`.
+    pub read_only: bool,
+    /// Cursor and scroll offset to restore when switching back to the buffer.
+    pub cursor: (usize, usize),
+    pub scroll: (usize, usize),
+    text: Rope,
+    disk_state: Option<(SystemTime, u64)>,
+    version: u64,
+    history: History,
+    /// Chunks of a large file still being read in the background.
+    loader: Option<Receiver<std::io::Result<Vec<u8>>>>,
+    /// Set until the whole file has been read, and for good if reading
+    /// failed; the text is then only part of the file and can't be saved.
+    partial: bool,
 }
 
 impl Buffer {
     pub fn new(file: Option<impl Into<PathBuf>>, contents: &str) -> Self {
-        let lines = if contents.is_empty() {
-            vec![String::new()]
-        } else {
-            contents.lines().map(String::from).collect()
-        };
-
         Self {
             file: file.map(Into::into),
-            lines,
             is_modified: false,
+            end_of_line: contents.ends_with('\n'),
+            line_ending: LineEnding::detect(contents),
+            marks: HashMap::new(),
+            binary: false,
+            read_only: false,
+            cursor: (0, 0),
+            scroll: (0, 0),
+            text: rope_from_contents(contents, LineEnding::detect(contents)),
+            disk_state: None,
+            version: 0,
+            history: History::default(),
+            loader: None,
+            partial: false,
         }
     }
 
-    pub fn insert_new_line(&mut self, cy: usize, _cx: usize) {
-        self.lines.insert(cy, String::new());
-        self.is_modified = true;
+    pub fn insert_new_line(
+        &mut self,
+        cy: usize,
+        cx: usize,
+        auto_indent: bool,
+    ) -> anyhow::Result<usize> {
+        self.check_writable()?;
+        if cy >= self.len() {
+            return Err(anyhow::anyhow!("Invalid line index: {}", cy));
+        }
+
+        let line = self.get_line(cy);
+        let cx = cx.min(line.len());
+        let indent = if auto_indent {
+            self.indentation(cy)
+                .chars()
+                .take(line[..cx].chars().count())
+                .collect()
+        } else {
+            String::new()
+        };
+
+        let idx = self.char_index(cx, cy);
+        self.text.insert(idx, &format!("\n{}", indent));
+        self.mark_modified();
+        Ok(indent.len())
     }
 
     pub fn from_file(file: impl Into<PathBuf>) -> anyhow::Result<Self> {
@@ -33,97 +135,762 @@ impl Buffer {
         if !path.exists() {
             return Ok(Self {
                 file: Some(path),
-                lines: vec![String::new()],
                 is_modified: false,
+                end_of_line: true,
+                line_ending: LineEnding::Unix,
+                marks: HashMap::new(),
+                binary: false,
+                read_only: false,
+                cursor: (0, 0),
+                scroll: (0, 0),
+                text: Rope::new(),
+                disk_state: None,
+                version: 0,
+                history: History::default(),
+                loader: None,
+                partial: false,
             });
         }
 
-        let contents = std::fs::read_to_string(&path)
-            .with_context(|| format!("Failed to read file: {:?}", path))?;
+        let read = || -> std::io::Result<_> {
+            let mut reader = BufReader::new(File::open(&path)?);
+            if reader.get_ref().metadata()?.len() <= STREAM_SIZE {
+                let mut bytes = Vec::new();
+                reader.read_to_end(&mut bytes)?;
+                return Ok((bytes, None));
+            }
+
+            let first = read_chunk(&mut reader)?;
+            let (sender, receiver) = mpsc::channel();
+            std::thread::spawn(move || loop {
+                match read_chunk(&mut reader) {
+                    Ok(chunk) if chunk.is_empty() => break,
+                    Ok(chunk) => {
+                        if sender.send(Ok(chunk)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        let _ = sender.send(Err(e));
+                        break;
+                    }
+                }
+            });
+            Ok((first, Some(receiver)))
+        };
+        let (bytes, loader) = read().with_context(|| format!("Failed to read file: {:?}", path))?;
+        let (contents, binary) = decode(bytes);
+        let read_only = !is_writable(&path);
 
         Ok(Self {
+            disk_state: disk_state(&path),
             file: Some(path),
-            lines: if contents.is_empty() {
-                vec![String::new()]
-            } else {
-                contents.lines().map(String::from).collect()
-            },
             is_modified: false,
+            end_of_line: contents.ends_with('\n'),
+            line_ending: LineEnding::detect(&contents),
+            marks: HashMap::new(),
+            binary,
+            read_only,
+            cursor: (0, 0),
+            scroll: (0, 0),
+            text: rope_from_contents(&contents, LineEnding::detect(&contents)),
+            version: 0,
+            history: History::default(),
+            partial: loader.is_some(),
+            loader,
         })
     }
 
-    pub fn save(&mut self) -> anyhow::Result<()> {
-        if let Some(path) = &self.file {
-            let contents = self.lines.join("\n");
-            std::fs::write(path, contents)
-                .with_context(|| format!("Failed to save file: {:?}", path))?;
-            self.is_modified = false;
-            Ok(())
-        } else {
-            Err(anyhow::anyhow!("No file associated with this buffer"))
+    /// Whether the rest of the file is still being read.
+    pub fn is_loading(&self) -> bool {
+        self.loader.is_some()
+    }
+
+    /// Appends the chunks of the file read since the last call, returning
+    /// whether any were. Gives up for the frame after a short while so the
+    /// editor stays responsive.
+    pub fn load_more(&mut self) -> anyhow::Result<bool> {
+        let Some(loader) = self.loader.take() else {
+            return Ok(false);
+        };
+
+        let start = Instant::now();
+        let mut loaded = false;
+        while start.elapsed() < LOAD_BUDGET {
+            match loader.try_recv() {
+                Ok(Ok(bytes)) => {
+                    let (contents, binary) = decode(bytes);
+                    self.binary |= binary;
+                    // Every chunk but the last ends in a newline, which the
+                    // rope leaves off.
+                    let end = self.text.len_chars();
+                    self.text.insert(end, "\n");
+                    self.text
+                        .append(rope_from_contents(&contents, self.line_ending));
+                    self.end_of_line = contents.ends_with('\n');
+                    self.version += 1;
+                    loaded = true;
+                }
+                Ok(Err(e)) => {
+                    let path = self.file.as_deref().unwrap_or(Path::new(""));
+                    return Err(e).with_context(|| format!("Failed to read file: {:?}", path));
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.partial = false;
+                    return Ok(loaded);
+                }
+            }
         }
+        self.loader = Some(loader);
+        Ok(loaded)
     }
 
-    pub fn save_as(&mut self, path: impl Into<PathBuf>) -> anyhow::Result<()> {
+    /// Writes the buffer to its file. With `backup`, an existing file is
+    /// first copied to `file~`.
+    pub fn save(&mut self, force: bool, backup: bool) -> anyhow::Result<()> {
+        let Some(path) = self.file.clone() else {
+            return Err(anyhow::anyhow!("No file associated with this buffer"));
+        };
+
+        if !force && self.read_only {
+            return Err(anyhow::anyhow!("File is read-only (add ! to override)"));
+        }
+        if !force && self.disk_state.is_some() && self.disk_state != disk_state(&path) {
+            return Err(anyhow::anyhow!("File changed on disk (add ! to override)"));
+        }
+
+        self.write_to(&path, backup)?;
+        self.disk_state = disk_state(&path);
+        self.mark_saved();
+        Ok(())
+    }
+
+    /// Writes the buffer to `path` and makes that its file. Without `force`,
+    /// an existing file other than the buffer's own is left alone.
+    pub fn save_as(
+        &mut self,
+        path: impl Into<PathBuf>,
+        force: bool,
+        backup: bool,
+    ) -> anyhow::Result<()> {
         let path = path.into();
-        let contents = self.lines.join("\n");
-        std::fs::write(&path, contents)
-            .with_context(|| format!("Failed to save file: {:?}", path))?;
+        let own_file = self.file.as_deref().is_some_and(|file| file == path);
+        if !force && !own_file && path.exists() {
+            return Err(anyhow::anyhow!("File exists (add ! to override)"));
+        }
+        self.write_to(&path, backup)?;
+        self.disk_state = disk_state(&path);
         self.file = Some(path);
+        self.mark_saved();
+        Ok(())
+    }
+
+    /// Every other state in the history now differs from the file.
+    fn mark_saved(&mut self) {
         self.is_modified = false;
+        let history = &mut self.history;
+        for snapshot in history
+            .undo
+            .iter_mut()
+            .chain(history.redo.iter_mut())
+            .chain(
+                history
+                    .change_start
+                    .iter_mut()
+                    .map(|(snapshot, _)| snapshot),
+            )
+        {
+            snapshot.modified = true;
+        }
+    }
+
+    fn snapshot(&self, cursor: (usize, usize)) -> Snapshot {
+        Snapshot {
+            text: self.text.clone(),
+            cursor,
+            modified: self.is_modified,
+        }
+    }
+
+    /// Starts a change that will be undone as one step, remembering the
+    /// text as it is now.
+    pub fn begin_change(&mut self, cursor: (usize, usize)) {
+        self.history.change_start = Some((self.snapshot(cursor), self.version));
+    }
+
+    /// Ends the change started by `begin_change`, adding it to the undo list
+    /// if the text was edited.
+    pub fn end_change(&mut self) {
+        if let Some((snapshot, version)) = self.history.change_start.take() {
+            if version != self.version {
+                self.history.undo.push(snapshot);
+                self.history.redo.clear();
+            }
+        }
+    }
+
+    /// Reverts the last change, returning the cursor position from before
+    /// it, or None if there is nothing to undo.
+    pub fn undo(&mut self, cursor: (usize, usize)) -> Option<(usize, usize)> {
+        let snapshot = self.history.undo.pop()?;
+        let current = self.snapshot(cursor);
+        self.history.redo.push(current);
+        Some(self.restore(snapshot))
+    }
+
+    /// Reapplies the last undone change, returning the cursor position from
+    /// when it was undone, or None if there is nothing to redo.
+    pub fn redo(&mut self, cursor: (usize, usize)) -> Option<(usize, usize)> {
+        let snapshot = self.history.redo.pop()?;
+        let current = self.snapshot(cursor);
+        self.history.undo.push(current);
+        Some(self.restore(snapshot))
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> (usize, usize) {
+        self.text = snapshot.text;
+        self.is_modified = snapshot.modified;
+        self.version += 1;
+        self.history.change_start = None;
+        snapshot.cursor
+    }
+
+    fn write_to(&self, path: &Path, backup: bool) -> anyhow::Result<()> {
+        if self.binary {
+            anyhow::bail!("Not saving: file isn't valid UTF-8 and would be corrupted");
+        }
+        if self.partial {
+            anyhow::bail!("Not saving: file hasn't been read completely");
+        }
+
+        if backup && path.exists() {
+            std::fs::copy(path, sibling_path(path, "", "~"))
+                .with_context(|| format!("Failed to write backup of {:?}", path))?;
+        }
+
+        // Write next to the target and rename over it, so a failed write
+        // never leaves the file truncated. Symlinks are resolved first so the
+        // link itself isn't replaced by a regular file.
+        let target = resolve_symlink(path);
+        let permissions = std::fs::metadata(&target).ok().map(|m| m.permissions());
+        let temp = sibling_path(&target, ".", &format!(".{}.tmp", std::process::id()));
+        let write = |file: File| -> std::io::Result<()> {
+            let mut writer = BufWriter::new(file);
+            match self.line_ending {
+                LineEnding::Unix => self.text.write_to(&mut writer)?,
+                LineEnding::Dos => {
+                    for chunk in self.text.chunks() {
+                        writer.write_all(chunk.replace('\n', "\r\n").as_bytes())?;
+                    }
+                }
+            }
+            if self.end_of_line {
+                writer.write_all(self.line_ending.as_str().as_bytes())?;
+            }
+            writer.flush()?;
+            writer.get_ref().sync_all()
+        };
+        let result = match File::create(&temp) {
+            Ok(file) => {
+                let result = match permissions {
+                    Some(permissions) => file.set_permissions(permissions),
+                    None => Ok(()),
+                };
+                let result = result
+                    .and_then(|()| write(file))
+                    .and_then(|()| std::fs::rename(&temp, &target));
+                if result.is_err() {
+                    let _ = std::fs::remove_file(&temp);
+                }
+                result
+            }
+            // The directory may not be writable even when the file is, so
+            // write over the file itself instead.
+            Err(_) => File::create(&target).and_then(write),
+        };
+        result.with_context(|| format!("Failed to save file: {:?}", path))
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    fn check_writable(&self) -> anyhow::Result<()> {
+        if self.read_only {
+            return Err(anyhow::anyhow!("Buffer is read-only"));
+        }
+        if self.partial {
+            return Err(anyhow::anyhow!("File hasn't been read completely"));
+        }
         Ok(())
     }
 
+    fn mark_modified(&mut self) {
+        self.is_modified = true;
+        self.version += 1;
+    }
+
     pub fn len(&self) -> usize {
-        self.lines.len()
+        self.text.len_lines()
+    }
+
+    pub fn get_line(&self, idx: usize) -> Cow<'_, str> {
+        if idx >= self.len() {
+            return Cow::Borrowed("");
+        }
+
+        let line = self.text.line(idx);
+        let line = match line.len_chars() {
+            len if len > 0 && line.char(len - 1) == '\n' => line.slice(..len - 1),
+            _ => line,
+        };
+        match line.as_str() {
+            Some(s) => Cow::Borrowed(s),
+            None => Cow::Owned(line.to_string()),
+        }
     }
 
-    pub fn get_line(&self, idx: usize) -> &str {
-        &self.lines[idx]
+    pub fn get_lines(&self, range: Range<usize>) -> Vec<String> {
+        range.map(|y| self.get_line(y).into_owned()).collect()
+    }
+
+    fn char_index(&self, cx: usize, cy: usize) -> usize {
+        self.text.byte_to_char(self.text.line_to_byte(cy) + cx)
     }
 
     pub fn insert_char(&mut self, cx: usize, cy: usize, c: char) -> anyhow::Result<()> {
-        if cy >= self.lines.len() {
+        self.check_writable()?;
+        if cy >= self.len() {
             return Err(anyhow::anyhow!("Invalid line index: {}", cy));
         }
 
-        let line = &mut self.lines[cy];
-        if cx > line.len() {
+        if cx > self.get_line(cy).len() {
             return Err(anyhow::anyhow!("Invalid column index: {}", cx));
         }
 
-        line.insert(cx, c);
+        let idx = self.char_index(cx, cy);
+        self.text.insert_char(idx, c);
+        self.mark_modified();
         Ok(())
     }
 
     pub fn remove_char(&mut self, cx: usize, cy: usize) -> anyhow::Result<()> {
-        if cy >= self.lines.len() {
+        self.check_writable()?;
+        if cy >= self.len() {
             return Err(anyhow::anyhow!("Invalid line index: {}", cy));
         }
 
-        let line = &mut self.lines[cy];
-        if cx >= line.len() {
+        if cx >= self.get_line(cy).len() {
             return Err(anyhow::anyhow!("Invalid column index: {}", cx));
         }
 
-        line.remove(cx);
-        self.is_modified = true;
+        let idx = self.char_index(cx, cy);
+        self.text.remove(idx..idx + 1);
+        self.mark_modified();
+        Ok(())
+    }
+
+    pub fn replace_char(&mut self, cx: usize, cy: usize, c: char) -> anyhow::Result<()> {
+        self.check_writable()?;
+        if cy >= self.len() {
+            return Err(anyhow::anyhow!("Invalid line index: {}", cy));
+        }
+
+        let line_len = self.get_line(cy).len();
+        if cx > line_len {
+            return Err(anyhow::anyhow!("Invalid column index: {}", cx));
+        }
+
+        let idx = self.char_index(cx, cy);
+        if cx < line_len {
+            self.text.remove(idx..idx + 1);
+        }
+        self.text.insert_char(idx, c);
+        self.mark_modified();
         Ok(())
     }
 
     pub fn remove_line(&mut self, cy: usize) -> anyhow::Result<String> {
-        if cy >= self.lines.len() {
+        self.check_writable()?;
+        if cy >= self.len() {
             return Err(anyhow::anyhow!("Invalid line index: {}", cy));
         }
 
-        if self.lines.len() == 1 {
-            let line = std::mem::take(&mut self.lines[0]);
-            self.is_modified = true;
-            return Ok(line);
+        Ok(self.remove_lines(cy..cy + 1)?.remove(0))
+    }
+
+    pub fn insert_lines(&mut self, cy: usize, lines: Vec<String>) -> anyhow::Result<()> {
+        self.check_writable()?;
+        if cy > self.len() {
+            return Err(anyhow::anyhow!("Invalid line index: {}", cy));
         }
 
-        self.is_modified = true;
-        Ok(self.lines.remove(cy))
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        let text = lines.join("\n");
+        if cy == self.len() {
+            self.text
+                .insert(self.text.len_chars(), &format!("\n{}", text));
+        } else {
+            self.text
+                .insert(self.text.line_to_char(cy), &format!("{}\n", text));
+        }
+        self.mark_modified();
+        Ok(())
+    }
+
+    pub fn insert_str(&mut self, cx: usize, cy: usize, text: &str) -> anyhow::Result<(usize, usize)> {
+        self.check_writable()?;
+        if cy >= self.len() {
+            return Err(anyhow::anyhow!("Invalid line index: {}", cy));
+        }
+        if cx > self.get_line(cy).len() {
+            return Err(anyhow::anyhow!("Invalid column index: {}", cx));
+        }
+
+        let idx = self.char_index(cx, cy);
+        self.text.insert(idx, text);
+        self.mark_modified();
+
+        let end = match text.rfind('\n') {
+            Some(pos) => (text.len() - pos - 1, cy + text.matches('\n').count()),
+            None => (cx + text.len(), cy),
+        };
+        Ok(end)
+    }
+
+    /// The start of the character before byte `cx` of line `cy`, or 0 when
+    /// there is none.
+    pub fn char_start(&self, cx: usize, cy: usize) -> usize {
+        let line = self.get_line(cy);
+        line[..line.floor_char_boundary(cx)]
+            .char_indices()
+            .next_back()
+            .map_or(0, |(x, _)| x)
+    }
+
+    pub fn char_end(&self, cx: usize, cy: usize) -> usize {
+        let line = self.get_line(cy);
+        line.get(cx..)
+            .and_then(|rest| rest.chars().next())
+            .map_or(line.len(), |c| cx + c.len_utf8())
+    }
+
+    pub fn indentation(&self, cy: usize) -> String {
+        self.get_line(cy)
+            .chars()
+            .take_while(|c| c == &' ' || c == &'\t')
+            .collect()
+    }
+
+    pub fn word_end(&self, cx: usize, cy: usize) -> usize {
+        let line = self.get_line(cy);
+        let rest = line.get(cx..).unwrap_or_default();
+        let mut chars = rest.char_indices();
+        let Some((_, first)) = chars.next() else {
+            return line.len();
+        };
+
+        let class = char_class(first);
+        chars
+            .find(|&(_, c)| char_class(c) != class)
+            .map_or(line.len(), |(i, _)| cx + i)
+    }
+
+    pub fn next_word_start(&self, cx: usize, cy: usize) -> usize {
+        let end = self.word_end(cx, cy);
+        let line = self.get_line(cy);
+        line[end..]
+            .char_indices()
+            .find(|(_, c)| !c.is_whitespace())
+            .map_or(line.len(), |(i, _)| end + i)
+    }
+
+    pub fn previous_word_start(&self, cx: usize, cy: usize) -> usize {
+        let line = self.get_line(cy);
+        let before = line.get(..cx).unwrap_or(&line);
+        let mut chars = before
+            .char_indices()
+            .rev()
+            .skip_while(|(_, c)| c.is_whitespace());
+        let Some((mut start, first)) = chars.next() else {
+            return 0;
+        };
+
+        let class = char_class(first);
+        for (i, c) in chars {
+            if char_class(c) != class {
+                break;
+            }
+            start = i;
+        }
+        start
+    }
+
+    pub fn text_range(&self, start: (usize, usize), end: (usize, usize)) -> String {
+        let start = self.char_index(start.0, start.1);
+        let end = self.char_index(end.0, end.1);
+        self.text.slice(start..end).to_string()
+    }
+
+    pub fn remove_range(&mut self, start: (usize, usize), end: (usize, usize)) -> anyhow::Result<String> {
+        self.check_writable()?;
+        let (sx, sy) = start;
+        let (ex, ey) = end;
+        if sy > ey || ey >= self.len() {
+            return Err(anyhow::anyhow!("Invalid line range: {}..{}", sy, ey));
+        }
+        if sx > self.get_line(sy).len() || ex > self.get_line(ey).len() || (sy == ey && sx > ex) {
+            return Err(anyhow::anyhow!("Invalid column range: {}..{}", sx, ex));
+        }
+
+        let text = self.text_range(start, end);
+        let start = self.char_index(sx, sy);
+        let end = self.char_index(ex, ey);
+        self.text.remove(start..end);
+        self.mark_modified();
+        Ok(text)
+    }
+
+    pub fn remove_lines(&mut self, range: Range<usize>) -> anyhow::Result<Vec<String>> {
+        self.check_writable()?;
+        let end = range.end.min(self.len());
+        let start = range.start.min(end);
+        if start == end {
+            return Ok(Vec::new());
+        }
+
+        let removed = self.get_lines(start..end);
+        let chars = if end < self.len() {
+            self.text.line_to_char(start)..self.text.line_to_char(end)
+        } else if start > 0 {
+            self.text.line_to_char(start) - 1..self.text.len_chars()
+        } else {
+            0..self.text.len_chars()
+        };
+        self.text.remove(chars);
+        self.mark_modified();
+        Ok(removed)
+    }
+
+    /// Puts `lines` in place of the lines in `range` as a single edit,
+    /// returning the lines that were replaced.
+    pub fn replace_lines(
+        &mut self,
+        range: Range<usize>,
+        lines: Vec<String>,
+    ) -> anyhow::Result<Vec<String>> {
+        self.check_writable()?;
+        if range.start > range.end || range.end > self.len() {
+            return Err(anyhow::anyhow!(
+                "Invalid line range: {}..{}",
+                range.start,
+                range.end
+            ));
+        }
+        if range.is_empty() {
+            self.insert_lines(range.start, lines)?;
+            return Ok(Vec::new());
+        }
+        if lines.is_empty() {
+            return self.remove_lines(range);
+        }
+
+        let replaced = self.get_lines(range.clone());
+        let start = self.char_index(0, range.start);
+        let end = self.char_index(self.get_line(range.end - 1).len(), range.end - 1);
+        self.text.remove(start..end);
+        self.text.insert(start, &lines.join("\n"));
+        self.mark_modified();
+        Ok(replaced)
+    }
+
+    pub fn display_column(&self, cx: usize, cy: usize, tab_width: usize) -> usize {
+        let line = self.get_line(cy);
+        // A cursor inside a character is drawn on that character.
+        line[..line.floor_char_boundary(cx)]
+            .chars()
+            .fold(0, |col, c| {
+                if c == '\t' {
+                    col + tab_width - col % tab_width
+                } else {
+                    col + 1
+                }
+            })
+    }
+
+    pub fn column_to_index(&self, col: usize, cy: usize, tab_width: usize) -> usize {
+        let line = self.get_line(cy);
+        let mut current = 0;
+        for (x, c) in line.char_indices() {
+            current += if c == '\t' {
+                tab_width - current % tab_width
+            } else {
+                1
+            };
+            if current > col {
+                return x;
+            }
+        }
+        line.len()
+    }
+
+    pub fn matching_bracket(&self, cx: usize, cy: usize) -> Option<(usize, usize)> {
+        self.matching_bracket_within(cx, cy, 0..self.len())
+    }
+
+    /// Like `matching_bracket`, but gives up once the search leaves `lines`.
+    pub fn matching_bracket_within(
+        &self,
+        cx: usize,
+        cy: usize,
+        lines: Range<usize>,
+    ) -> Option<(usize, usize)> {
+        let line = self.get_line(cy);
+        let (offset, bracket) = line
+            .get(cx..)?
+            .char_indices()
+            .find(|&(_, c)| "()[]{}".contains(c))?;
+        let (open, close, forward) = match bracket {
+            '(' => ('(', ')', true),
+            '[' => ('[', ']', true),
+            '{' => ('{', '}', true),
+            ')' => ('(', ')', false),
+            ']' => ('[', ']', false),
+            _ => ('{', '}', false),
+        };
+
+        let start = self.char_index(cx + offset, cy);
+        let first = self
+            .text
+            .line_to_char(lines.start.min(self.text.len_lines()));
+        let end = self.text.line_to_char(lines.end.min(self.text.len_lines()));
+        let mut chars = self.text.chars_at(if forward { start + 1 } else { start });
+        let mut index = start;
+        let mut depth = 1usize;
+        while depth > 0 {
+            if (forward && index + 1 >= end) || (!forward && index <= first) {
+                return None;
+            }
+            let c = if forward {
+                let c = chars.next()?;
+                index += 1;
+                c
+            } else {
+                let c = chars.prev()?;
+                index -= 1;
+                c
+            };
+
+            if c == open {
+                depth = if forward { depth + 1 } else { depth - 1 };
+            } else if c == close {
+                depth = if forward { depth - 1 } else { depth + 1 };
+            }
+        }
+
+        let byte = self.text.char_to_byte(index);
+        let y = self.text.byte_to_line(byte);
+        Some((byte - self.text.line_to_byte(y), y))
+    }
+
+    pub fn find_forward(&self, pattern: &Regex, cx: usize, cy: usize) -> Option<(usize, usize)> {
+        if cy >= self.len() {
+            return None;
+        }
+
+        let start = self.char_end(cx, cy);
+        let after_cursor = find_matches(&self.get_line(cy), pattern)
+            .into_iter()
+            .map(|r| r.start)
+            .find(|&x| x >= start);
+        if let Some(x) = after_cursor {
+            return Some((x, cy));
+        }
+
+        let len = self.len();
+        (1..=len).find_map(|offset| {
+            let y = (cy + offset) % len;
+            find_matches(&self.get_line(y), pattern)
+                .first()
+                .map(|r| (r.start, y))
+        })
+    }
+
+    pub fn find_backward(&self, pattern: &Regex, cx: usize, cy: usize) -> Option<(usize, usize)> {
+        if cy >= self.len() {
+            return None;
+        }
+
+        let before_cursor = find_matches(&self.get_line(cy), pattern)
+            .into_iter()
+            .map(|r| r.start)
+            .take_while(|&x| x < cx)
+            .last();
+        if let Some(x) = before_cursor {
+            return Some((x, cy));
+        }
+
+        let len = self.len();
+        (1..=len).find_map(|offset| {
+            let y = (cy + len - offset) % len;
+            find_matches(&self.get_line(y), pattern)
+                .last()
+                .map(|r| (r.start, y))
+        })
+    }
+
+    fn set_line(&mut self, cy: usize, line: &str) {
+        let start = self.text.line_to_char(cy);
+        let end = start + self.get_line(cy).chars().count();
+        self.text.remove(start..end);
+        self.text.insert(start, line);
+        self.mark_modified();
+    }
+
+    pub fn swap_lines(&mut self, a: usize, b: usize) -> anyhow::Result<()> {
+        self.check_writable()?;
+        if a >= self.len() || b >= self.len() {
+            return Err(anyhow::anyhow!("Invalid line index: {}", a.max(b)));
+        }
+
+        let line_a = self.get_line(a).into_owned();
+        let line_b = self.get_line(b).into_owned();
+        self.set_line(a, &line_b);
+        self.set_line(b, &line_a);
+        Ok(())
+    }
+
+    pub fn substitute(
+        &mut self,
+        cy: usize,
+        pattern: &Regex,
+        replacement: &str,
+        global: bool,
+    ) -> anyhow::Result<usize> {
+        self.check_writable()?;
+        if cy >= self.len() {
+            return Ok(0);
+        }
+
+        let line = self.get_line(cy);
+        let count = if global {
+            pattern.find_iter(&line).count()
+        } else {
+            usize::from(pattern.is_match(&line))
+        };
+        if count > 0 {
+            let limit = if global { 0 } else { 1 };
+            let replaced = pattern
+                .replacen(&line, limit, regex::NoExpand(replacement))
+                .into_owned();
+            self.set_line(cy, &replaced);
+        }
+
+        Ok(count)
     }
 
     pub fn file_name(&self) -> Option<String> {
@@ -135,3 +902,264 @@ impl Buffer {
     }
 }
 
+/// Whether the user may write `path`. Unlike the mode bits, this accounts for
+/// ownership, ACLs and read-only mounts, and unlike opening the file it can't
+/// block on a FIFO or wake up anything watching it.
+#[cfg(unix)]
+fn is_writable(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: `path` is a NUL-terminated string that outlives the call.
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_writable(path: &Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|metadata| !metadata.permissions().readonly())
+}
+
+/// The file a chain of symlinks at `path` ends at, or `path` itself.
+fn resolve_symlink(path: &Path) -> PathBuf {
+    let mut path = path.to_path_buf();
+    // Give up on loops rather than follow them forever.
+    for _ in 0..40 {
+        let Ok(link) = std::fs::read_link(&path) else {
+            break;
+        };
+        path = match path.parent() {
+            Some(parent) => parent.join(link),
+            None => link,
+        };
+    }
+    path
+}
+
+/// `path` with its file name wrapped in `prefix` and `suffix`.
+fn sibling_path(path: &Path, prefix: &str, suffix: &str) -> PathBuf {
+    let mut name = std::ffi::OsString::from(prefix);
+    name.push(path.file_name().unwrap_or_default());
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+fn disk_state(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// About `CHUNK_SIZE` bytes from `reader`, continued to the end of the line
+/// so chunks never split a line or a character. Empty at the end of the file.
+fn read_chunk(reader: &mut impl BufRead) -> std::io::Result<Vec<u8>> {
+    let mut chunk = Vec::new();
+    reader.by_ref().take(CHUNK_SIZE).read_to_end(&mut chunk)?;
+    if chunk.last().is_some_and(|&b| b != b'\n') {
+        reader.read_until(b'\n', &mut chunk)?;
+    }
+    Ok(chunk)
+}
+
+/// The text of `bytes`, and whether it had to be decoded lossily.
+fn decode(bytes: Vec<u8>) -> (String, bool) {
+    match String::from_utf8(bytes) {
+        Ok(contents) => (contents, false),
+        Err(e) => (String::from_utf8_lossy(e.as_bytes()).into_owned(), true),
+    }
+}
+
+/// Builds the rope with lines joined by `\n`. Only the detected line ending is
+/// taken off each line, so a stray `\r` stays part of the text.
+fn rope_from_contents(contents: &str, line_ending: LineEnding) -> Rope {
+    let mut builder = RopeBuilder::new();
+    for (i, line) in contents.split_inclusive('\n').enumerate() {
+        if i > 0 {
+            builder.append("\n");
+        }
+        let line = match line.strip_suffix('\n') {
+            Some(line) if line_ending == LineEnding::Dos => line.strip_suffix('\r').unwrap_or(line),
+            Some(line) => line,
+            None => line,
+        };
+        builder.append(line);
+    }
+    builder.finish()
+}
+
+pub fn find_matches(line: &str, pattern: &Regex) -> Vec<Range<usize>> {
+    pattern.find_iter(line).map(|m| m.range()).collect()
+}
+
+fn char_class(c: char) -> u8 {
+    if c.is_whitespace() {
+        0
+    } else if c.is_alphanumeric() || c == '_' {
+        1
+    } else {
+        2
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A path in the temporary directory that is unique to this run and
+    /// removed, file or directory, when dropped.
+    pub(crate) struct TempPath(PathBuf);
+
+    impl TempPath {
+        pub(crate) fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!("ziv-{}-{}", std::process::id(), name)))
+        }
+    }
+
+    impl std::ops::Deref for TempPath {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for TempPath {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl From<&TempPath> for PathBuf {
+        fn from(path: &TempPath) -> Self {
+            path.0.clone()
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            if self.0.is_dir() {
+                let _ = std::fs::remove_dir_all(&self.0);
+            } else {
+                let _ = std::fs::remove_file(&self.0);
+            }
+        }
+    }
+
+    fn round_trip(name: &str, contents: &str) -> String {
+        let path = TempPath::new(name);
+        std::fs::write(&path, contents).unwrap();
+        let mut buffer = Buffer::from_file(&path).unwrap();
+        buffer.save(true, false).unwrap();
+        std::fs::read_to_string(&path).unwrap()
+    }
+
+    #[test]
+    fn keeps_unix_line_endings() {
+        assert_eq!(round_trip("lf", "one\ntwo\n"), "one\ntwo\n");
+    }
+
+    #[test]
+    fn keeps_dos_line_endings() {
+        let buffer = Buffer::new(None::<PathBuf>, "one\r\ntwo\r\n");
+        assert_eq!(buffer.get_line(0), "one");
+        assert_eq!(round_trip("crlf", "one\r\ntwo\r\n"), "one\r\ntwo\r\n");
+    }
+
+    #[test]
+    fn keeps_a_stray_carriage_return_as_text() {
+        let buffer = Buffer::new(None::<PathBuf>, "one\r\ntwo\nthree\n");
+        assert_eq!(buffer.get_line(0), "one\r");
+        assert_eq!(
+            round_trip("mixed", "one\r\ntwo\nthree"),
+            "one\r\ntwo\nthree"
+        );
+    }
+
+    #[test]
+    fn refuses_to_save_over_another_file_unless_forced() {
+        let path = TempPath::new("save-as");
+        std::fs::write(&path, "theirs\n").unwrap();
+        let mut buffer = Buffer::new(None::<PathBuf>, "ours\n");
+        assert!(buffer.save_as(&path, false, false).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "theirs\n");
+        buffer.save_as(&path, true, false).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "ours\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn saves_through_symlinks_and_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempPath::new("links");
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("file");
+        let link = dir.join("link");
+        std::fs::write(&file, "old\n").unwrap();
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o640)).unwrap();
+        std::os::unix::fs::symlink("file", &link).unwrap();
+
+        let mut buffer = Buffer::from_file(&link).unwrap();
+        buffer.insert_char(0, 0, 'n').unwrap();
+        buffer.save(false, false).unwrap();
+        assert!(link.is_symlink());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "nold\n");
+        let mode = std::fs::metadata(&file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+    }
+
+    #[test]
+    fn writes_in_place_when_the_temporary_file_cannot_be_made() {
+        let dir = TempPath::new("in-place");
+        let file = dir.join("file");
+        // A directory where the temporary file would go makes creating it fail.
+        let temp = sibling_path(&file, ".", &format!(".{}.tmp", std::process::id()));
+        std::fs::create_dir_all(&temp).unwrap();
+        std::fs::write(&file, "old\n").unwrap();
+
+        let mut buffer = Buffer::from_file(&file).unwrap();
+        buffer.insert_char(0, 0, 'n').unwrap();
+        buffer.save(false, false).unwrap();
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "nold\n");
+    }
+
+    #[test]
+    fn checks_writability_without_truncating() {
+        let path = TempPath::new("writable");
+        std::fs::write(&path, "text\n").unwrap();
+        let buffer = Buffer::from_file(&path).unwrap();
+        assert!(!buffer.read_only);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "text\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn checks_a_fifo_for_writing_without_blocking() {
+        let path = TempPath::new("fifo");
+        let name = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+        // SAFETY: `name` is a NUL-terminated string that outlives the call.
+        assert_eq!(unsafe { libc::mkfifo(name.as_ptr(), 0o600) }, 0);
+        assert!(is_writable(&path));
+    }
+
+    #[test]
+    fn streams_in_a_large_file() {
+        let path = TempPath::new("large");
+        let contents: String = (0..1_000_000).map(|i| format!("line {}\n", i)).collect();
+        std::fs::write(&path, &contents).unwrap();
+
+        let mut buffer = Buffer::from_file(&path).unwrap();
+        assert!(buffer.is_loading());
+        assert!(buffer.len() < 1_000_000);
+        assert!(buffer.insert_char(0, 0, 'x').is_err());
+        assert!(buffer.save(true, false).is_err());
+        while buffer.is_loading() {
+            buffer.load_more().unwrap();
+        }
+
+        assert_eq!(buffer.len(), 1_000_000);
+        assert_eq!(buffer.get_line(999_999), "line 999999");
+        assert!(buffer.end_of_line && !buffer.is_modified);
+        buffer.insert_char(0, 0, 'x').unwrap();
+    }
+}
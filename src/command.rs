@@ -0,0 +1,220 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+pub struct Substitute {
+    pub pattern: String,
+    pub replacement: String,
+    pub whole_buffer: bool,
+    pub global: bool,
+    pub confirm: bool,
+}
+
+pub fn is_substitute(command: &str) -> bool {
+    command.strip_prefix('%').unwrap_or(command).starts_with("s/")
+}
+
+/// Splits a command line on `|`. Shell commands take the rest of the line,
+/// bars included, and a substitution keeps any bars up to its closing
+/// delimiter; elsewhere `\|` is a literal bar.
+pub fn split_commands(line: &str) -> Vec<String> {
+    let mut commands = Vec::new();
+    let mut current = String::new();
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            _ if takes_rest_of_line(&current) => current.push(c),
+            '\\' if chars.peek() == Some(&'|') => {
+                current.push('|');
+                chars.next();
+            }
+            '|' => commands.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    commands.push(current);
+
+    commands
+        .into_iter()
+        .map(|command| command.trim().to_string())
+        .filter(|command| !command.is_empty())
+        .collect()
+}
+
+fn takes_rest_of_line(command: &str) -> bool {
+    let command = command.trim_start();
+    let command = command
+        .strip_prefix("'<,'>")
+        .unwrap_or(command)
+        .trim_start();
+    if command.starts_with('!') {
+        return true;
+    }
+    if is_substitute(command) {
+        return !substitute_closed(command);
+    }
+    match command.split_once(char::is_whitespace) {
+        Some(("r" | "read", arg)) => arg.trim_start().starts_with('!'),
+        _ => false,
+    }
+}
+
+/// Whether a substitution has all three of its delimiters, so that only its
+/// flags can follow.
+fn substitute_closed(command: &str) -> bool {
+    let mut delimiters = 0;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '/' => delimiters += 1,
+            _ => {}
+        }
+    }
+    delimiters >= 3
+}
+
+pub fn parse_substitute(command: &str) -> anyhow::Result<Substitute> {
+    let (whole_buffer, rest) = match command.strip_prefix('%') {
+        Some(rest) => (true, rest),
+        None => (false, command),
+    };
+    let rest = rest
+        .strip_prefix("s/")
+        .ok_or_else(|| anyhow::anyhow!("Not a substitute command: {}", command))?;
+
+    let mut fields = split_unescaped(rest, '/').into_iter();
+    let pattern = fields.next().unwrap_or_default();
+    let replacement = fields.next().unwrap_or_default();
+    let flags = fields.next().unwrap_or_default();
+    if fields.next().is_some() {
+        anyhow::bail!("Trailing characters: {}", command);
+    }
+
+    let mut global = false;
+    let mut confirm = false;
+    for flag in flags.chars() {
+        match flag {
+            'g' => global = true,
+            'c' => confirm = true,
+            _ => anyhow::bail!("Invalid substitute flag: {}", flag),
+        }
+    }
+
+    Ok(Substitute {
+        pattern,
+        replacement,
+        whole_buffer,
+        global,
+        confirm,
+    })
+}
+
+fn split_unescaped(s: &str, delimiter: char) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        let field = fields.last_mut().unwrap();
+        match c {
+            '\\' => match chars.next() {
+                Some(next) if next == delimiter || next == '\\' => field.push(next),
+                Some(next) => {
+                    field.push('\\');
+                    field.push(next);
+                }
+                None => field.push('\\'),
+            },
+            c if c == delimiter => fields.push(String::new()),
+            c => field.push(c),
+        }
+    }
+
+    fields
+}
+
+pub fn complete_path(partial: &str) -> Vec<String> {
+    let (dir, prefix) = match partial.rfind('/') {
+        Some(i) => (&partial[..=i], &partial[i + 1..]),
+        None => ("", partial),
+    };
+    let Ok(entries) = std::fs::read_dir(if dir.is_empty() { "." } else { dir }) else {
+        return Vec::new();
+    };
+
+    let mut candidates: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            let suffix = if entry.path().is_dir() { "/" } else { "" };
+            Some(format!("{}{}{}", dir, name, suffix))
+        })
+        .collect();
+    candidates.sort();
+    candidates
+}
+
+pub fn run_shell(command: &str, input: Option<String>) -> anyhow::Result<String> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Write from a separate thread so a child that fills its stdout pipe
+    // before reading all of stdin can't deadlock us.
+    let writer = match (child.stdin.take(), input) {
+        (Some(mut stdin), Some(input)) => Some(std::thread::spawn(move || {
+            stdin.write_all(input.as_bytes())
+        })),
+        _ => None,
+    };
+
+    let output = child.wait_with_output()?;
+    if let Some(writer) = writer {
+        // The child may exit without reading its input; that isn't an error.
+        let _ = writer.join();
+    }
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match stderr.trim().lines().last() {
+            Some(line) => anyhow::bail!("{}", line),
+            None => anyhow::bail!("Command failed: {}", output.status),
+        }
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_after_a_substitution() {
+        assert_eq!(split_commands("%s/a/x/g | bn"), ["%s/a/x/g", "bn"]);
+        assert_eq!(split_commands("s/a|b/c\\/|d/|w"), ["s/a|b/c\\/|d/", "w"]);
+    }
+
+    #[test]
+    fn keeps_bars_in_an_unclosed_substitution() {
+        assert_eq!(split_commands("s/a/b|c"), ["s/a/b|c"]);
+    }
+
+    #[test]
+    fn keeps_bars_in_shell_commands() {
+        assert_eq!(split_commands("!sort | uniq"), ["!sort | uniq"]);
+        assert_eq!(split_commands("r !ls | wc -l"), ["r !ls | wc -l"]);
+        assert_eq!(split_commands("w | e \\| x"), ["w", "e | x"]);
+    }
+}
@@ -0,0 +1,65 @@
+use anyhow::Context;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub theme: String,
+    pub tab_width: usize,
+    pub expand_tab: bool,
+    pub auto_indent: bool,
+    pub number: bool,
+    pub keys: KeyBindings,
+}
+
+/// Extra key bindings per mode, mapping a key like `ctrl-s` to an action name.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct KeyBindings {
+    pub normal: HashMap<String, String>,
+    pub insert: HashMap<String, String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            theme: "base16-ocean.dark".to_string(),
+            tab_width: 4,
+            expand_tab: false,
+            auto_indent: true,
+            number: false,
+            keys: KeyBindings::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(config_dir.join("ziv").join("config.toml"))
+    }
+
+    pub fn load() -> anyhow::Result<Self> {
+        match Self::path() {
+            Some(path) if path.exists() => Self::from_file(path),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    pub fn from_file(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config: {:?}", path))?;
+        let config: Self = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse config: {:?}", path))?;
+
+        if config.tab_width == 0 {
+            anyhow::bail!("Invalid tab_width in config: {:?}", path);
+        }
+        Ok(config)
+    }
+}
@@ -1,33 +1,161 @@
 use anyhow::Result;
 use crossterm::{
     cursor,
-    event::{read, Event, KeyCode, KeyEvent, KeyModifiers},
-    style::{self, Color, Stylize},
-    terminal::{self, ClearType},
-    ExecutableCommand, QueueableCommand,
+    event::{poll, read, Event, KeyCode, KeyEvent, KeyModifiers},
+    style::Color,
+    QueueableCommand,
 };
+use regex::{Regex, RegexBuilder};
+use std::collections::HashMap;
 use std::io::{stdout, Write};
-use std::time::Instant;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use syntect::{
-    easy::HighlightLines,
-    highlighting::{Theme, ThemeSet},
-    parsing::SyntaxSet,
-    util::as_24_bit_terminal_escaped,
+    highlighting::{self as syntect_style, Theme, ThemeSet},
+    parsing::{SyntaxReference, SyntaxSet},
 };
 
-use crate::buffer::Buffer;
+use crate::buffer::{self, Buffer, LineEnding};
+use crate::command;
+use crate::config::Config;
+use crate::digraph;
+use crate::highlight::HighlightCache;
+use crate::keymap::{self, Key};
+use crate::register::RegisterContent;
+use crate::screen::{Screen, Style};
+use crate::session::{self, Session, SessionBuffer};
 
-#[derive(Debug, PartialEq)]
+const MAX_JUMPS: usize = 100;
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// How often to wake up for more of a file that is still loading.
+const LOAD_INTERVAL: Duration = Duration::from_millis(10);
+const YANK_FLASH: Duration = Duration::from_millis(150);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum Mode {
     Normal,
     Insert,
+    Replace,
     Command,
+    Search,
+    SaveAs,
+    Confirm,
+    Visual,
+    VisualLine,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Pending {
+    Replace,
+    Operator(Operator),
+    Find(Find),
+    SetMark,
+    JumpToMark(bool),
+    G,
+    Comment,
+    Z,
+    RightBracket,
+    Window,
+}
+
+/// A view onto a buffer. The focused window's cursor and scroll position
+/// live in the editor itself; this holds them for the others.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Window {
+    buffer: usize,
+    cx: usize,
+    cy: usize,
+    scroll_offset: usize,
+    left_col: usize,
+}
+
+/// The keys of a change for `.` to replay, and the count typed before them.
+#[derive(Clone, Default)]
+struct Change {
+    count: Option<usize>,
+    keys: Vec<KeyEvent>,
+}
+
+/// A `:s///c` in progress; the match being confirmed is at the cursor.
+struct Confirm {
+    query: String,
+    pattern: Regex,
+    /// The length of the match at the cursor.
+    len: usize,
+    replacement: String,
+    global: bool,
+    end: usize,
+    total: usize,
+    changed_lines: usize,
+    line_changed: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct VisualRange {
+    start: (usize, usize),
+    end: (usize, usize),
+    linewise: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ScrollAnchor {
+    Top,
+    Center,
+    Bottom,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Find {
+    Forward,
+    Backward,
+    TillForward,
+    TillBackward,
+}
+
+impl Find {
+    fn reversed(self) -> Self {
+        match self {
+            Find::Forward => Find::Backward,
+            Find::Backward => Find::Forward,
+            Find::TillForward => Find::TillBackward,
+            Find::TillBackward => Find::TillForward,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Delete,
+    Change,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Motion {
+    Line,
+    WordForward,
+    /// What `cw` changes: to the end of this many words.
+    WordEnd(usize),
+    EndOfLine,
+    StartOfLine,
+    /// The current line and this many lines below or above it.
+    LinesDown(usize),
+    LinesUp(usize),
 }
 
+#[derive(Clone)]
 enum Action {
+    /// What a key bound to `nop` does, shadowing any built-in command.
+    Nop,
     Quit,
-    Save,
-    SaveAs(String),
+    Save(bool),
+    SaveAs(String, bool),
+    OpenFile(String),
+    Reload(bool),
+    SplitWindow,
+    CloseWindow,
+    OnlyWindow,
+    FocusWindow(usize),
     MoveUp,
     MoveDown,
     MoveLeft,
@@ -36,73 +164,306 @@ enum Action {
     MoveEndOfLine,
     PageUp,
     PageDown,
+    ScrollUp,
+    ScrollDown,
+    ScrollCursorTo(ScrollAnchor),
     AddChar(char),
+    ReplaceChar(char),
+    ReplaceUnderCursor(char),
     NewLine,
+    Append,
+    AppendEndOfLine,
+    OpenLineBelow,
+    OpenLineAbove,
     DeleteChar,
+    DeleteUnderCursor,
+    DeleteWordBackward,
+    DeleteToLineStart,
+    InsertLiteral,
+    InsertDigraph,
+    DeleteBeforeCursor,
     DeleteLine,
+    ToggleCase,
+    ToggleCaseSelection,
+    Undo,
+    Redo,
+    Operate(Operator, Motion),
+    FindChar(Find, char),
+    RepeatFind(bool),
+    MatchBracket,
+    GoToPercent(usize),
+    /// The line numbered from 1, or the last line.
+    GoToLine(Option<usize>),
+    AddCursor(bool),
+    ClearCursors,
+    RepeatChange,
+    SetMark(char),
+    JumpToMark(char, bool),
+    JumpBack,
+    ToggleComment,
+    WordCount,
+    MoveLineDown,
+    MoveLineUp,
+    JumpForward,
+    Paste,
+    PasteIndented,
+    DeleteSelection,
+    YankSelection,
     EnterMode(Mode),
     NextBuffer,
+    NextModifiedBuffer(bool),
     PreviousBuffer,
+    CloseBuffer(bool),
     ExecuteCommand(String),
+    Search(String),
+    SearchNext,
+    SearchPrevious,
+}
+
+#[derive(PartialEq)]
+struct ViewState {
+    buffer: usize,
+    version: u64,
+    scroll_offset: usize,
+    left_col: usize,
+    size: (u16, u16),
+    tab_line: bool,
+    mode: Mode,
+    selection: Option<((usize, usize), (usize, usize))>,
+    search: Option<String>,
+    hl_search: Option<String>,
+    confirm: Option<(usize, usize)>,
+    brackets: Option<((usize, usize), (usize, usize))>,
+    yank_flash: bool,
+    extra_cursors: Vec<(usize, usize)>,
+    windows: Vec<(usize, usize, usize, u64)>,
+    active_window: usize,
 }
 
 pub struct Editor {
     buffers: Vec<Buffer>,
     active_buffer: usize,
-    stdout: std::io::Stdout,
+    windows: Vec<Window>,
+    active_window: usize,
+    screen: Screen,
     size: (u16, u16),
-    cx: u16,
-    cy: u16,
+    cx: usize,
+    cy: usize,
     mode: Mode,
     exit: bool,
-    scroll_offset: u16,
+    scroll_offset: usize,
+    left_col: usize,
     syntax_set: SyntaxSet,
+    theme_set: ThemeSet,
     theme: Theme,
+    highlight_caches: Vec<HighlightCache>,
     command_line: String,
+    command_history: Vec<String>,
+    search_history: Vec<String>,
+    history_index: Option<usize>,
+    completion: Option<(String, Vec<String>, usize)>,
+    visual_range: Option<VisualRange>,
+    confirm: Option<Confirm>,
+    yank_flash: Option<(VisualRange, Instant)>,
+    /// Cursors besides `cx`/`cy`, each on its own line.
+    extra_cursors: Vec<(usize, usize)>,
+    /// Set after Ctrl-V in insert mode, holding a `u`/`U` code being typed.
+    literal: Option<String>,
+    /// Set after Ctrl-K in insert mode, holding the first digraph character
+    /// once it's typed.
+    digraph: Option<Option<char>>,
     status_message: Option<(String, Instant)>,
+    command_failed: bool,
+    last_search: Option<String>,
+    hl_search: bool,
+    search_highlighted: bool,
+    last_find: Option<(Find, char)>,
+    desired_cx: Option<usize>,
+    /// Cursor and scroll offset to go back to when a search ends.
+    search_origin: Option<(usize, usize, usize)>,
+    register: Option<RegisterContent>,
+    anchor: (usize, usize),
+    pending: Option<Pending>,
+    count: Option<usize>,
+    operator_count: Option<usize>,
+    recording: Option<Change>,
+    last_change: Change,
+    insert_repeat: usize,
+    inserted: Vec<KeyEvent>,
+    jumps: Vec<(usize, usize, usize)>,
+    jump_index: usize,
+    normal_keys: HashMap<Key, Action>,
+    insert_keys: HashMap<Key, Action>,
+    tab_width: usize,
+    expand_tab: bool,
+    auto_indent: bool,
+    fix_end_of_line: bool,
+    backup: bool,
+    wrap: bool,
+    list: bool,
+    auto_pairs: bool,
+    scroll_off: usize,
+    show_tab_line: u8,
+    autosave: u64,
+    last_autosave: Instant,
+    color_columns: Vec<usize>,
+    ignore_case: bool,
+    smart_case: bool,
+    number: bool,
+    color: bool,
+    redraw: bool,
+    drawn_view: Option<ViewState>,
+    drawn_status: Option<(String, String)>,
+    brackets: Option<((usize, usize), (usize, usize))>,
 }
 
-impl Drop for Editor {
-    fn drop(&mut self) {
-        let _ = self.stdout.execute(cursor::Show);
-        let _ = self.stdout.execute(terminal::LeaveAlternateScreen);
-        let _ = terminal::disable_raw_mode();
-        let _ = self.stdout.flush();
+impl Editor {
+    /// Creates an editor for a screen of `size`. Nothing here touches the
+    /// terminal; only `run` reads keys and draws, so an editor can also be
+    /// driven with `handle_key` alone.
+    pub fn new(buffers: Vec<Buffer>, size: (u16, u16)) -> Result<Self> {
+        let (config, config_error) = match Config::load() {
+            Ok(config) => (config, None),
+            Err(e) => (Config::default(), Some(format!("{:#}", e))),
+        };
+
+        let mut editor = Self::with_config(buffers, size, config)?;
+        if let Some(msg) = config_error {
+            editor.set_status_message(msg);
+        }
+        Ok(editor)
     }
-}
 
-impl Editor {
-    pub fn new(buffers: Vec<Buffer>) -> Result<Self> {
+    /// Like `new`, but with `config` instead of the user's config file.
+    pub fn with_config(buffers: Vec<Buffer>, size: (u16, u16), config: Config) -> Result<Self> {
         if buffers.is_empty() {
             anyhow::bail!("At least one buffer is required");
         }
 
-        let mut stdout = stdout();
-        terminal::enable_raw_mode()?;
-        stdout
-            .execute(terminal::EnterAlternateScreen)?
-            .execute(terminal::Clear(ClearType::All))?;
-        stdout.execute(cursor::Show)?;
-
         let syntax_set = SyntaxSet::load_defaults_newlines();
         let theme_set = ThemeSet::load_defaults();
-        let theme = theme_set.themes["base16-ocean.dark"].clone();
+        let theme = theme_set.themes[&Config::default().theme].clone();
 
-        Ok(Editor {
+        let mut editor = Editor {
             buffers,
             active_buffer: 0,
-            stdout,
+            windows: vec![Window {
+                buffer: 0,
+                cx: 0,
+                cy: 0,
+                scroll_offset: 0,
+                left_col: 0,
+            }],
+            active_window: 0,
             cx: 0,
             cy: 0,
             mode: Mode::Normal,
-            size: terminal::size()?,
+            size,
+            screen: Screen::new(size),
             exit: false,
             scroll_offset: 0,
+            left_col: 0,
             syntax_set,
+            theme_set,
             theme,
+            highlight_caches: Vec::new(),
             command_line: String::new(),
+            command_history: Vec::new(),
+            search_history: Vec::new(),
+            history_index: None,
+            completion: None,
+            visual_range: None,
+            confirm: None,
+            yank_flash: None,
+            extra_cursors: Vec::new(),
+            literal: None,
+            digraph: None,
             status_message: None,
-        })
+            command_failed: false,
+            last_search: None,
+            hl_search: false,
+            search_highlighted: false,
+            last_find: None,
+            desired_cx: None,
+            search_origin: None,
+            register: None,
+            anchor: (0, 0),
+            pending: None,
+            count: None,
+            operator_count: None,
+            recording: None,
+            last_change: Change::default(),
+            insert_repeat: 0,
+            inserted: Vec::new(),
+            jumps: Vec::new(),
+            jump_index: 0,
+            normal_keys: HashMap::new(),
+            insert_keys: HashMap::new(),
+            tab_width: 4,
+            expand_tab: false,
+            auto_indent: true,
+            fix_end_of_line: false,
+            backup: false,
+            wrap: false,
+            list: false,
+            auto_pairs: false,
+            scroll_off: 5,
+            show_tab_line: 1,
+            autosave: 0,
+            last_autosave: Instant::now(),
+            color_columns: Vec::new(),
+            ignore_case: false,
+            smart_case: false,
+            number: false,
+            color: color_enabled(),
+            redraw: true,
+            drawn_view: None,
+            drawn_status: None,
+            brackets: None,
+        };
+
+        if let Some(msg) = editor.apply_config(&config) {
+            editor.set_status_message(msg);
+        }
+        editor.restore_view();
+
+        Ok(editor)
+    }
+
+    /// Takes the settings and key bindings from `config`, returning the first
+    /// problem found in it.
+    fn apply_config(&mut self, config: &Config) -> Option<String> {
+        let mut errors = Vec::new();
+        self.normal_keys = build_keymap(default_normal_keys(), &config.keys.normal, &mut errors);
+        self.insert_keys = build_keymap(default_insert_keys(), &config.keys.insert, &mut errors);
+
+        match self.theme_set.themes.get(&config.theme) {
+            Some(theme) => {
+                self.theme = theme.clone();
+                self.highlight_caches.clear();
+            }
+            None => errors.insert(0, format!("Unknown theme: {}", config.theme)),
+        }
+        self.tab_width = config.tab_width;
+        self.expand_tab = config.expand_tab;
+        self.auto_indent = config.auto_indent;
+        self.number = config.number;
+        self.redraw = true;
+        errors.into_iter().next()
+    }
+
+    fn source_config(&mut self, path: Option<&str>) {
+        let config = match path {
+            Some(path) => Config::from_file(path),
+            None => Config::load(),
+        };
+        match config {
+            Ok(config) => match self.apply_config(&config) {
+                Some(msg) => self.set_error_message(msg),
+                None => self.set_status_message("Config reloaded".to_string()),
+            },
+            Err(e) => self.set_error_message(format!("{:#}", e)),
+        }
     }
 
     fn current_buffer(&self) -> &Buffer {
@@ -113,350 +474,4119 @@ impl Editor {
         &mut self.buffers[self.active_buffer]
     }
 
+    /// Strips colors from `style` when color output is disabled.
+    fn ui_style(&self, style: Style) -> Style {
+        if self.color {
+            style
+        } else {
+            Style {
+                fg: Color::Reset,
+                bg: Color::Reset,
+                ..style
+            }
+        }
+    }
+
     fn set_status_message(&mut self, msg: String) {
         self.status_message = Some((msg, Instant::now()));
     }
 
-    fn visible_lines(&self) -> u16 {
-        self.size.1.saturating_sub(2)
+    /// Like `set_status_message`, but also stops a `|` chain of commands.
+    fn set_error_message(&mut self, msg: String) {
+        self.set_status_message(msg);
+        self.command_failed = true;
     }
 
-    fn adjust_scroll(&mut self) {
-        let visible_lines = self.visible_lines();
+    fn visible_lines(&self) -> usize {
+        self.window_rect(self.active_window).1 as usize
+    }
 
-        if self.cy < self.scroll_offset {
-            self.scroll_offset = self.cy;
-        }
+    /// Top row and height of a window. Windows are stacked vertically with a
+    /// separator line below each one but the last.
+    fn window_rect(&self, index: usize) -> (u16, u16) {
+        let top = self.tab_line_height();
+        let count = self.windows.len() as u16;
+        let available = self
+            .size
+            .1
+            .saturating_sub(2 + top)
+            .saturating_sub(count - 1);
+        let (height, extra) = (available / count, available % count);
+        let window_height = |i: u16| height + u16::from(i < extra);
+
+        let y = top + (0..index as u16).map(|i| window_height(i) + 1).sum::<u16>();
+        (y, window_height(index as u16))
+    }
 
-        if self.cy >= self.scroll_offset + visible_lines {
-            self.scroll_offset = self.cy - visible_lines + 1;
+    fn current_window(&self) -> Window {
+        Window {
+            buffer: self.active_buffer,
+            cx: self.cx,
+            cy: self.cy,
+            scroll_offset: self.scroll_offset,
+            left_col: self.left_col,
         }
     }
 
-    fn adjust_cursor_position(&mut self) {
-        let max_cy = self.current_buffer().len().saturating_sub(1) as u16;
-        self.cy = self.cy.min(max_cy);
+    fn load_window(&mut self, index: usize) {
+        let window = self.windows[index];
+        self.active_window = index;
+        self.active_buffer = window.buffer.min(self.buffers.len() - 1);
+        // The buffer may have shrunk while another window was editing it.
+        let max_cy = self.current_buffer().len().saturating_sub(1);
+        self.cy = window.cy.min(max_cy);
+        self.cx = window.cx.min(self.max_cx());
+        self.scroll_offset = window.scroll_offset.min(self.cy);
+        self.left_col = window.left_col;
+    }
 
-        if self.mode != Mode::Insert {
-            let line_len = self.current_buffer().get_line(self.cy as usize).len();
-            self.cx = self.cx.min(line_len.saturating_sub(1) as u16);
-        }
+    fn swap_window(&mut self, index: usize) {
+        self.windows[self.active_window] = self.current_window();
+        self.load_window(index);
+    }
 
+    fn split_window(&mut self) {
+        self.windows
+            .insert(self.active_window, self.current_window());
         self.adjust_scroll();
     }
 
-    fn clear_screen(&mut self) -> Result<()> {
-        self.stdout
-            .queue(terminal::Clear(ClearType::All))?
-            .queue(cursor::MoveTo(0, 0))?;
-        Ok(())
+    fn close_window(&mut self) {
+        if self.windows.len() == 1 {
+            self.set_error_message("Cannot close last window".to_string());
+            return;
+        }
+        self.windows.remove(self.active_window);
+        self.load_window(self.active_window.min(self.windows.len() - 1));
+        self.desired_cx = None;
     }
 
-    fn draw_status_line(&mut self) -> Result<()> {
-        let (width, height) = self.size;
-        let file_name = self
-            .current_buffer()
+    fn draw_windows(&mut self) {
+        let focused = self.active_window;
+        self.highlight_caches
+            .resize_with(self.windows.len(), HighlightCache::default);
+        for index in 0..self.windows.len() {
+            self.swap_window(index);
+            self.draw_buffer(index == focused);
+            if index + 1 < self.windows.len() {
+                self.draw_window_separator(index == focused);
+            }
+        }
+        self.swap_window(focused);
+    }
+
+    fn draw_window_separator(&mut self, focused: bool) {
+        let (top, height) = self.window_rect(self.active_window);
+        let y = top + height;
+        let buffer = self.current_buffer();
+        let name = buffer
             .file_name()
             .unwrap_or_else(|| "[No Name]".to_string());
-        let modified = if self.current_buffer().is_modified {
-            "[+]"
-        } else {
-            ""
-        };
-        let status = match self.mode {
-            Mode::Normal => format!("NORMAL {} {}", file_name, modified),
-            Mode::Insert => format!("INSERT {} {}", file_name, modified),
-            Mode::Command => format!(":{}", self.command_line),
-        };
+        let modified = if buffer.is_modified { " [+]" } else { "" };
+        let label = format!(" {}{}", name, modified);
 
-        let mut stdout = self.stdout.lock();
+        let style = self.ui_style(Style {
+            fg: Color::White,
+            bg: Color::DarkGrey,
+            bold: focused,
+            reverse: !self.color,
+        });
+        self.screen.clear_row(y);
+        self.screen
+            .put_str(0, y, &format!("{:<1$}", label, self.size.0 as usize), style);
+    }
 
-        stdout.queue(cursor::MoveTo(0, height - 1))?;
+    fn tab_line_height(&self) -> u16 {
+        let shown = match self.show_tab_line {
+            0 => false,
+            1 => self.buffers.len() > 1,
+            _ => true,
+        };
+        u16::from(shown)
+    }
 
-        stdout.queue(terminal::Clear(ClearType::CurrentLine))?;
+    fn draw_tab_line(&mut self) {
+        if self.tab_line_height() == 0 {
+            return;
+        }
 
-        stdout.queue(style::PrintStyledContent(
-            status.clone().bold().with(Color::White).on(Color::Blue),
-        ))?;
+        let width = self.size.0 as usize;
+        let labels: Vec<String> = self
+            .buffers
+            .iter()
+            .map(|buffer| {
+                let name = buffer
+                    .file_name()
+                    .unwrap_or_else(|| "[No Name]".to_string());
+                let modified = if buffer.is_modified { " [+]" } else { "" };
+                format!(" {}{} ", name, modified)
+            })
+            .collect();
 
-        if let Some((msg, time)) = &self.status_message {
-            if time.elapsed().as_secs() < 5 {
-                let right_status = format!(" {}", msg);
-                let padding = width as usize - status.len() - right_status.len();
-                if padding > 0 {
-                    stdout.queue(style::Print(" ".repeat(padding)))?.queue(
-                        style::PrintStyledContent(right_status.with(Color::White).on(Color::Blue)),
-                    )?;
-                }
-            }
+        // Drop tabs from the left until the active one fits on screen.
+        let mut start = 0;
+        while start < self.active_buffer
+            && labels[start..=self.active_buffer]
+                .iter()
+                .map(|label| label.chars().count())
+                .sum::<usize>()
+                > width
+        {
+            start += 1;
         }
 
-        stdout.flush()?;
+        let inactive = self.ui_style(Style {
+            fg: Color::White,
+            bg: Color::DarkGrey,
+            ..Style::default()
+        });
+        let active = Style {
+            bg: Color::Blue,
+            bold: true,
+            reverse: !self.color,
+            ..inactive
+        };
+        let active = self.ui_style(active);
 
-        Ok(())
+        self.screen.clear_row(0);
+        self.screen.put_str(0, 0, &" ".repeat(width), inactive);
+        let mut x = 0;
+        if start > 0 {
+            x = self.screen.put_str(x, 0, "<", inactive);
+        }
+        for (i, label) in labels.iter().enumerate().skip(start) {
+            let style = if i == self.active_buffer {
+                active
+            } else {
+                inactive
+            };
+            x = self.screen.put_str(x, 0, label, style);
+        }
+        let total = usize::from(start > 0)
+            + labels[start..]
+                .iter()
+                .map(|label| label.chars().count())
+                .sum::<usize>();
+        if total > width {
+            self.screen
+                .put_str(width.saturating_sub(1) as u16, 0, ">", inactive);
+        }
     }
 
-    fn draw_buffer(&mut self) -> Result<()> {
-        self.clear_screen()?;
-
+    fn adjust_scroll(&mut self) {
         let visible_lines = self.visible_lines();
-        let syntax = self
-            .syntax_set
-            .find_syntax_by_extension("rs")
-            .or_else(|| self.syntax_set.find_syntax_by_extension("txt"))
-            .unwrap_or_else(|| self.syntax_set.find_syntax_plain_text());
+        let margin = self.scroll_margin();
+
+        // Like vim, a jump that lands well outside the view, such as a search,
+        // `G` or an undo, centers the cursor line instead of scrolling just
+        // far enough to show it.
+        let half = visible_lines / 2;
+        if self.cy.saturating_add(half) < self.scroll_offset
+            || self.cy >= self.scroll_offset.saturating_add(visible_lines + half)
+        {
+            self.center_cursor();
+        }
+
+        let top = self.cy.saturating_sub(margin);
+        if top < self.scroll_offset {
+            self.scroll_offset = top;
+        }
+
+        if self.wrap {
+            self.left_col = 0;
+            if !self.margin_on_screen() {
+                self.scroll_offset = self.wrapped_top(self.margin_bottom());
+            }
+            return;
+        }
 
-        let mut highlighter = HighlightLines::new(syntax, &self.theme);
+        let bottom = self.margin_bottom();
+        if bottom >= self.scroll_offset + visible_lines {
+            self.scroll_offset = bottom + 1 - visible_lines;
+        }
 
-        let mut stdout = self.stdout.lock();
+        let col = self.cursor_column();
+        let text_width = self.text_width();
+        if col < self.left_col {
+            self.left_col = col;
+        } else if col >= self.left_col + text_width {
+            self.left_col = col + 1 - text_width;
+        }
+    }
 
-        for y in 0..visible_lines {
-            let line_index = (self.scroll_offset + y) as usize;
+    fn text_width(&self) -> usize {
+        (self.size.0 as usize)
+            .saturating_sub(self.gutter_width())
+            .max(1)
+    }
 
-            stdout.queue(cursor::MoveTo(0, y))?;
+    fn cursor_column(&self) -> usize {
+        self.current_buffer()
+            .display_column(self.cx, self.cy, self.tab_width)
+    }
 
-            if line_index < self.current_buffer().len() {
-                let line = self.current_buffer().get_line(line_index);
-                let ranges = highlighter.highlight_line(line, &self.syntax_set);
+    /// How many lines `scrolloff` keeps around the cursor, limited so the
+    /// margins above and below can both fit in the window.
+    fn scroll_margin(&self) -> usize {
+        let limit = self.visible_lines().saturating_sub(1) / 2;
+        limit.min(self.scroll_off)
+    }
 
-                match ranges {
-                    Ok(ranges) => {
-                        let escaped = as_24_bit_terminal_escaped(&ranges[..], true);
-                        stdout.queue(style::Print(escaped))?;
-                    }
-                    Err(e) => {
-                        stdout.queue(style::Print(line))?;
-                        eprintln!("Error highlighting line: {}", e);
-                    }
-                }
+    /// The last line that has to be visible to keep the bottom margin.
+    fn margin_bottom(&self) -> usize {
+        let last = self.current_buffer().len().saturating_sub(1);
+        self.cy
+            .saturating_add(self.scroll_margin())
+            .min(last)
+            .max(self.cy)
+    }
+
+    /// The highest line that the view can start at in wrap mode while still
+    /// showing line `bottom`, and never below the cursor line. Only the
+    /// cursor's own row counts when `bottom` is the cursor line.
+    fn wrapped_top(&self, bottom: usize) -> usize {
+        let visible_lines = self.visible_lines();
+        let mut rows = 0;
+        let mut top = bottom;
+        loop {
+            rows += if top == self.cy && bottom == self.cy {
+                self.cursor_row_in_line() + 1
             } else {
-                stdout.queue(style::Print("~"))?;
+                self.line_rows(top)
+            };
+            if rows > visible_lines {
+                top += 1;
+                break;
             }
+            if top == 0 {
+                break;
+            }
+            top -= 1;
+        }
+        top.min(self.cy)
+    }
 
-            stdout.queue(terminal::Clear(ClearType::UntilNewLine))?;
+    fn margin_on_screen(&self) -> bool {
+        let bottom = self.margin_bottom();
+        if !self.wrap || bottom == self.cy {
+            return self.cursor_on_screen() && bottom < self.scroll_offset + self.visible_lines();
         }
+        // Every line takes at least a row, so this is off screen without
+        // adding up the rows in between.
+        if bottom >= self.scroll_offset + self.visible_lines() {
+            return false;
+        }
+        let rows: usize = (self.scroll_offset..=bottom)
+            .map(|y| self.line_rows(y))
+            .sum();
+        self.cursor_on_screen() && rows <= self.visible_lines()
+    }
 
-        stdout.flush()?;
+    fn cursor_on_screen(&self) -> bool {
+        let visible_lines = self.visible_lines();
+        self.cy >= self.scroll_offset
+            && self.cy - self.scroll_offset < visible_lines
+            && self.cursor_row() < visible_lines
+    }
 
-        Ok(())
+    /// Where the terminal cursor goes: after the typed text while a prompt is
+    /// open, otherwise on the cursor's cell in the active window.
+    fn cursor_position(&self) -> (u16, u16) {
+        let prompt = match self.mode {
+            Mode::Command | Mode::Search => Some(1),
+            Mode::SaveAs => Some("Save as: ".len()),
+            _ => None,
+        };
+        if let Some(prompt) = prompt {
+            let x = prompt + self.command_line.chars().count();
+            let x = x.min(self.size.0.saturating_sub(1) as usize) as u16;
+            return (x, self.size.1.saturating_sub(1));
+        }
+
+        let (top, _) = self.window_rect(self.active_window);
+        let column = self.cursor_column();
+        let column = if self.wrap {
+            column % self.text_width()
+        } else {
+            column.saturating_sub(self.left_col)
+        };
+        let x = (self.gutter_width() + column).min(u16::MAX as usize) as u16;
+        let y = top as usize + self.cursor_row();
+        (x, y.min(u16::MAX as usize) as u16)
     }
 
-    fn handle_command(&mut self, command: &str) -> Result<()> {
-        let parts: Vec<&str> = command.split_whitespace().collect();
-        if parts.is_empty() {
-            return Ok(());
+    fn cursor_row(&self) -> usize {
+        if self.wrap {
+            let above: usize = (self.scroll_offset..self.cy)
+                .map(|y| self.line_rows(y))
+                .sum();
+            above + self.cursor_row_in_line()
+        } else {
+            self.cy.saturating_sub(self.scroll_offset)
         }
+    }
 
-        match parts[0] {
-            "w" | "write" => {
-                if parts.len() > 1 {
-                    self.handle_action(Action::SaveAs(parts[1].to_string()))?;
-                } else {
-                    self.handle_action(Action::Save)?;
-                }
-            }
-            "q" | "quit" => {
-                if !self.current_buffer().is_modified {
-                    self.handle_action(Action::Quit)?;
-                } else {
-                    self.set_status_message(
-                        "No write since last change (add ! to override)".to_string(),
-                    );
-                }
-            }
-            "q!" | "quit!" => {
-                self.handle_action(Action::Quit)?;
-            }
-            "wq" => {
-                self.handle_action(Action::Save)?;
-                self.handle_action(Action::Quit)?;
-            }
-            _ => {
-                self.set_status_message(format!("Unknown command: {}", command));
-            }
+    /// Which of the cursor line's wrapped rows the cursor is on.
+    fn cursor_row_in_line(&self) -> usize {
+        if self.wrap {
+            self.cursor_column() / self.text_width()
+        } else {
+            0
         }
-        Ok(())
     }
 
-    pub fn run(&mut self) -> Result<()> {
-        loop {
-            self.adjust_cursor_position();
-            self.draw_buffer()?;
-            self.draw_status_line()?;
+    fn scroll_cursor_to(&mut self, anchor: ScrollAnchor) {
+        let visible_lines = self.visible_lines();
+        let target = match anchor {
+            ScrollAnchor::Top => 0,
+            ScrollAnchor::Center => visible_lines / 2,
+            ScrollAnchor::Bottom => visible_lines.saturating_sub(1),
+        };
 
-            if self.exit {
+        let mut rows = self.cursor_row_in_line();
+        self.scroll_offset = self.cy;
+        while self.scroll_offset > 0 {
+            let above = if self.wrap {
+                self.line_rows(self.scroll_offset - 1)
+            } else {
+                1
+            };
+            if rows + above > target {
                 break;
             }
+            rows += above;
+            self.scroll_offset -= 1;
+        }
+    }
 
-            self.stdout.flush()?;
-
-            if let Event::Key(key) = read()? {
-                match self.mode {
-                    Mode::Normal => self.handle_normal_key(key)?,
-                    Mode::Insert => self.handle_insert_key(key)?,
-                    Mode::Command => self.handle_command_key(key)?,
-                }
-            }
+    /// Centers the cursor line without scrolling past the end of the buffer.
+    fn center_cursor(&mut self) {
+        let visible_lines = self.visible_lines();
+        self.scroll_cursor_to(ScrollAnchor::Center);
+        if !self.wrap {
+            let last_top = self.current_buffer().len().saturating_sub(visible_lines);
+            self.scroll_offset = self.scroll_offset.min(last_top);
         }
-        Ok(())
     }
 
-    fn handle_normal_key(&mut self, key: KeyEvent) -> Result<()> {
-        let action = match (key.code, key.modifiers) {
-            (KeyCode::Char(':'), _) => Some(Action::EnterMode(Mode::Command)),
-            (KeyCode::Char('i'), _) => Some(Action::EnterMode(Mode::Insert)),
-            (KeyCode::Up | KeyCode::Char('k'), _) => Some(Action::MoveUp),
-            (KeyCode::Down | KeyCode::Char('j'), _) => Some(Action::MoveDown),
-            (KeyCode::Left | KeyCode::Char('h'), _) => Some(Action::MoveLeft),
-            (KeyCode::Right | KeyCode::Char('l'), _) => Some(Action::MoveRight),
-            (KeyCode::Char('0'), _) => Some(Action::MoveStartOfLine),
-            (KeyCode::Char('$'), _) => Some(Action::MoveEndOfLine),
-            (KeyCode::Char('n'), _) => Some(Action::NextBuffer),
-            (KeyCode::Char('p'), _) => Some(Action::PreviousBuffer),
-            (KeyCode::Char('d'), KeyModifiers::CONTROL) => Some(Action::PageDown),
-            (KeyCode::Char('u'), KeyModifiers::CONTROL) => Some(Action::PageUp),
-            (KeyCode::Char('w'), KeyModifiers::CONTROL) => Some(Action::Save),
-            (KeyCode::Char('d'), _) => Some(Action::DeleteLine),
-            _ => None,
+    fn line_rows(&self, y: usize) -> usize {
+        let buffer = self.current_buffer();
+        let width = buffer.display_column(buffer.get_line(y).len(), y, self.tab_width);
+        width.div_ceil(self.text_width()).max(1)
+    }
+
+    fn move_visual_row(&mut self, down: bool) {
+        let text_width = self.text_width();
+        let col = self.cursor_column();
+        let row = col / text_width;
+        let row_col = self
+            .desired_cx
+            .unwrap_or(col % text_width)
+            .min(text_width - 1);
+        self.desired_cx = Some(row_col);
+
+        let (y, row) = if down {
+            if row + 1 < self.line_rows(self.cy) {
+                (self.cy, row + 1)
+            } else if self.cy + 1 < self.current_buffer().len() {
+                (self.cy + 1, 0)
+            } else {
+                return;
+            }
+        } else if row > 0 {
+            (self.cy, row - 1)
+        } else if self.cy > 0 {
+            (self.cy - 1, self.line_rows(self.cy - 1) - 1)
+        } else {
+            return;
         };
 
-        if let Some(action) = action {
-            self.handle_action(action)?;
+        self.cy = y;
+        self.cx =
+            self.current_buffer()
+                .column_to_index(row * text_width + row_col, y, self.tab_width);
+    }
+
+    fn adjust_cursor_position(&mut self) {
+        let max_cy = self.current_buffer().len().saturating_sub(1);
+        self.cy = self.cy.min(max_cy);
+
+        self.cx = self.cx.min(self.max_cx());
+        self.adjust_scroll();
+    }
+
+    fn max_cx(&self) -> usize {
+        let cy = self.cy;
+        let line_len = self.current_buffer().get_line(cy).len();
+        if matches!(self.mode, Mode::Insert | Mode::Replace) {
+            line_len
+        } else {
+            // Normal mode stays on the last character, not inside it.
+            self.current_buffer().char_start(line_len, cy)
         }
-        Ok(())
     }
 
-    fn handle_insert_key(&mut self, key: KeyEvent) -> Result<()> {
-        match key.code {
-            KeyCode::Esc => self.handle_action(Action::EnterMode(Mode::Normal))?,
-            KeyCode::Enter => self.handle_action(Action::NewLine)?,
-            KeyCode::Backspace => {
-                if self.cx > 0 {
-                    self.cx -= 1;
-                    self.handle_action(Action::DeleteChar)?;
-                }
-            }
-            KeyCode::Char(c) => self.handle_action(Action::AddChar(c))?,
-            _ => {}
+    fn gutter_width(&self) -> usize {
+        if self.number {
+            self.current_buffer().len().to_string().len().max(3) + 1
+        } else {
+            0
         }
-        Ok(())
     }
 
-    fn handle_command_key(&mut self, key: KeyEvent) -> Result<()> {
-        match key.code {
-            KeyCode::Esc => {
-                self.command_line.clear();
-                self.handle_action(Action::EnterMode(Mode::Normal))?;
-            }
-            KeyCode::Enter => {
-                let command = std::mem::take(&mut self.command_line);
-                self.handle_command(&command)?;
-                self.handle_action(Action::EnterMode(Mode::Normal))?;
-            }
-            KeyCode::Backspace => {
-                if !self.command_line.is_empty() {
-                    self.command_line.pop();
+    fn draw_status_line(&mut self) {
+        let (width, height) = self.size;
+        let file_name = self
+            .current_buffer()
+            .file_name()
+            .unwrap_or_else(|| "[No Name]".to_string());
+        let buffer = self.current_buffer();
+        let modified = [
+            (buffer.binary, "[binary]"),
+            (buffer.is_modified, "[+]"),
+            (buffer.read_only, "[RO]"),
+            (buffer.is_loading(), "[loading]"),
+        ]
+        .iter()
+        .filter(|&&(on, _)| on)
+        .map(|&(_, flag)| flag)
+        .collect::<Vec<_>>()
+        .join(" ");
+        let status = match self.mode {
+            Mode::Normal => format!("NORMAL {} {}", file_name, modified),
+            Mode::Insert => format!("INSERT {} {}", file_name, modified),
+            Mode::Replace => format!("REPLACE {} {}", file_name, modified),
+            Mode::Visual => format!("VISUAL {} {}", file_name, modified),
+            Mode::VisualLine => format!("V-LINE {} {}", file_name, modified),
+            Mode::Command => format!(":{}", self.command_line),
+            Mode::Search => format!("/{}", self.command_line),
+            Mode::SaveAs => format!("Save as: {}", self.command_line),
+            Mode::Confirm => format!(
+                "replace with {} (y/n/a/q)?",
+                self.confirm
+                    .as_ref()
+                    .map_or("", |confirm| confirm.replacement.as_str())
+            ),
+        };
+
+        let width = width as usize;
+        let status: String = status.chars().take(width).collect();
+        let position = format!(
+            " {},{}  {} ",
+            self.cy + 1,
+            self.cx + 1,
+            self.scroll_position()
+        );
+        let right_status = match self
+            .status_message
+            .as_ref()
+            .filter(|(_, time)| time.elapsed().as_secs() < 5)
+        {
+            Some((msg, _)) => {
+                let available = width.saturating_sub(status.chars().count() + position.len() + 3);
+                let msg: String = msg.chars().take(available).collect();
+                if msg.is_empty() {
+                    position
+                } else {
+                    format!(" {} {}", msg, position)
                 }
             }
-            KeyCode::Char(c) => {
-                self.command_line.push(c);
-            }
-            _ => {}
+            None => position,
+        };
+        // On a screen too narrow for both, the mode and file name win.
+        let right_status = if status.chars().count() + right_status.chars().count() < width {
+            right_status
+        } else {
+            String::new()
+        };
+
+        let drawn = (status, right_status);
+        if self.drawn_status.as_ref() == Some(&drawn) {
+            return;
         }
-        Ok(())
+        let style = self.ui_style(Style {
+            fg: Color::White,
+            bg: Color::Blue,
+            reverse: !self.color,
+            ..Style::default()
+        });
+        let (status, right_status) = self.drawn_status.insert(drawn);
+
+        let y = height.saturating_sub(1);
+
+        self.screen.clear_row(y);
+        self.screen.put_str(
+            0,
+            y,
+            status,
+            Style {
+                bold: true,
+                ..style
+            },
+        );
+
+        self.screen.put_str(
+            (width - right_status.chars().count()) as u16,
+            y,
+            right_status,
+            style,
+        );
     }
 
-    fn handle_action(&mut self, action: Action) -> Result<()> {
-        match action {
-            Action::Quit => {
-                self.exit = true;
+    fn scroll_position(&self) -> String {
+        let above = self.scroll_offset;
+        let below = self
+            .current_buffer()
+            .len()
+            .saturating_sub(above + self.visible_lines());
+
+        match (above, below) {
+            (0, 0) => "All".to_string(),
+            (_, 0) => "Bot".to_string(),
+            (0, _) => "Top".to_string(),
+            _ => format!("{}%", above * 100 / (above + below)),
+        }
+    }
+
+    fn draw_buffer(&mut self, focused: bool) {
+        let (top, visible_lines) = self.window_rect(self.active_window);
+        let syntax = detect_syntax(&self.syntax_set, self.current_buffer());
+        let lines = self.scroll_offset..self.scroll_offset + visible_lines as usize;
+        self.highlight_caches[self.active_window].begin(
+            syntax,
+            &self.buffers[self.active_buffer],
+            lines,
+        );
+
+        let gutter_width = self.gutter_width();
+        let text_width = self.text_width();
+        let search = self.incremental_pattern().filter(|_| focused).or_else(|| {
+            self.hl_search_query()
+                .and_then(|query| self.search_pattern(query).ok())
+        });
+        let brackets = self.brackets.filter(|_| focused);
+
+        let mut y = 0;
+        let mut line_index = self.scroll_offset;
+        while y < visible_lines {
+            if line_index >= self.current_buffer().len() {
+                self.screen.clear_row(top + y);
+                let style = self.ui_style(Style {
+                    fg: Color::Blue,
+                    bold: true,
+                    ..Style::default()
+                });
+                self.screen.put_str(0, top + y, "~", style);
+                y += 1;
+                continue;
             }
-            Action::Save => {
-                self.current_buffer_mut().save()?;
-                self.set_status_message("File saved".to_string());
+
+            let line = self.current_buffer().get_line(line_index).into_owned();
+            let mut highlights = search
+                .as_ref()
+                .map_or_else(Vec::new, |pattern| buffer::find_matches(&line, pattern));
+            if focused {
+                highlights.extend(self.selection_highlight(line_index, &line));
+                highlights.extend(self.yank_highlight(line_index, &line));
+                highlights.extend(self.extra_cursor_highlight(line_index, &line));
+                highlights.extend(self.confirm_highlight(line_index));
             }
-            Action::SaveAs(path) => {
-                self.current_buffer_mut().save_as(path)?;
-                self.set_status_message("File saved as".to_string());
+
+            let ranges = if self.color {
+                let buffer = &self.buffers[self.active_buffer];
+                self.highlight_caches[self.active_window]
+                    .line(line_index, buffer, syntax, &self.syntax_set, &self.theme)
+                    .iter()
+                    .map(|(style, range)| (*style, &line[range.clone()]))
+                    .collect()
+            } else {
+                vec![(syntect_style::Style::default(), line.as_str())]
+            };
+            let mut cells = Vec::new();
+            let mut col = 0;
+            for (style, text, highlighted) in overlay_segments(&ranges, &highlights) {
+                let style = self.ui_style(Style {
+                    fg: to_color(style.foreground),
+                    bg: to_color(style.background),
+                    reverse: highlighted,
+                    ..Style::default()
+                });
+                let text = expand_tabs(text, &mut col, self.tab_width, self.list);
+                cells.extend(text.chars().map(|c| (c, style)));
             }
-            Action::MoveUp => {
-                if self.cy > 0 {
-                    self.cy -= 1;
+
+            for (x, _) in brackets
+                .iter()
+                .flat_map(|&(a, b)| [a, b])
+                .filter(|&(_, y)| y == line_index)
+            {
+                let col = self
+                    .current_buffer()
+                    .display_column(x, line_index, self.tab_width);
+                if let Some((_, style)) = cells.get_mut(col) {
+                    *style = self.ui_style(Style {
+                        bg: Color::DarkCyan,
+                        bold: true,
+                        reverse: !self.color,
+                        ..*style
+                    });
+                }
+            }
+
+            if self.list {
+                let trimmed = line.trim_end_matches(' ').len();
+                let trailing =
+                    self.current_buffer()
+                        .display_column(trimmed, line_index, self.tab_width);
+                for (c, _) in cells.iter_mut().skip(trailing) {
+                    *c = '·';
+                }
+            }
+
+            let rows: Vec<&[(char, Style)]> = if self.wrap {
+                let mut rows: Vec<_> = cells.chunks(text_width).collect();
+                if rows.is_empty() {
+                    rows.push(&[]);
+                }
+                rows
+            } else {
+                vec![cells.get(self.left_col..).unwrap_or_default()]
+            };
+
+            for (i, row) in rows.into_iter().enumerate() {
+                if y >= visible_lines {
+                    break;
+                }
+                self.screen.clear_row(top + y);
+
+                let mut x = 0;
+                if gutter_width > 0 {
+                    let number = if i == 0 {
+                        format!("{:>1$} ", line_index + 1, gutter_width - 1)
+                    } else {
+                        " ".repeat(gutter_width)
+                    };
+                    let style = self.ui_style(Style {
+                        fg: Color::DarkGrey,
+                        ..Style::default()
+                    });
+                    x = self.screen.put_str(x, top + y, &number, style);
+                }
+
+                let text_x = x;
+                for &(c, style) in row.iter().take(text_width) {
+                    self.screen.put_char(x, top + y, c, style);
+                    x += 1;
+                }
+
+                let row_start = if self.wrap {
+                    i * text_width
+                } else {
+                    self.left_col
+                };
+                for &column in self.color_columns.iter().filter(|_| self.color) {
+                    if let Some(offset) = (column - 1).checked_sub(row_start) {
+                        if offset < text_width {
+                            self.screen.set_background(
+                                text_x + offset as u16,
+                                top + y,
+                                Color::DarkGrey,
+                            );
+                        }
+                    }
+                }
+                y += 1;
+            }
+            line_index += 1;
+        }
+
+        let buffer = self.current_buffer();
+        if buffer.file.is_none()
+            && !buffer.is_modified
+            && buffer.len() == 1
+            && buffer.get_line(0).is_empty()
+        {
+            self.draw_welcome(top, visible_lines);
+        }
+    }
+
+    /// Draws the intro text over an empty, unnamed buffer.
+    fn draw_welcome(&mut self, top: u16, height: u16) {
+        let version = format!("version {}", env!("CARGO_PKG_VERSION"));
+        let lines = [
+            "ziv",
+            "",
+            version.as_str(),
+            "",
+            "type  i            to start inserting",
+            "type  :e <file>    to open a file",
+            "type  :w <file>    to save",
+            "type  :q           to quit",
+        ];
+        if height < lines.len() as u16 + 2 {
+            return;
+        }
+
+        let style = self.ui_style(Style {
+            fg: Color::DarkGrey,
+            ..Style::default()
+        });
+        let width = lines.iter().map(|line| line.len()).max().unwrap_or(0) as u16;
+        let start = top + (height - lines.len() as u16) / 2;
+        for (i, line) in lines.iter().enumerate() {
+            // Short lines are centered, the hints share a left edge.
+            let len = if line.starts_with("type") {
+                width
+            } else {
+                line.len() as u16
+            };
+            let x = self.size.0.saturating_sub(len) / 2;
+            self.screen.put_str(x, start + i as u16, line, style);
+        }
+    }
+
+    fn search_pattern(&self, query: &str) -> Result<Regex, regex::Error> {
+        let (pattern, literal) = match query.strip_prefix("\\V") {
+            Some(rest) => (rest, true),
+            None => (query, false),
+        };
+        let ignore_case =
+            self.ignore_case && !(self.smart_case && pattern.chars().any(char::is_uppercase));
+        let pattern = if literal {
+            regex::escape(pattern)
+        } else {
+            pattern.to_string()
+        };
+
+        RegexBuilder::new(&pattern)
+            .case_insensitive(ignore_case)
+            .build()
+    }
+
+    /// The last search while `hlsearch` is on and `:noh` hasn't hidden it.
+    fn hl_search_query(&self) -> Option<&str> {
+        self.last_search
+            .as_deref()
+            .filter(|_| self.hl_search && self.search_highlighted)
+    }
+
+    fn incremental_pattern(&self) -> Option<Regex> {
+        if self.mode != Mode::Search || self.command_line.is_empty() {
+            return None;
+        }
+        self.search_pattern(&self.command_line).ok()
+    }
+
+    fn selection(&self) -> Option<((usize, usize), (usize, usize))> {
+        if !matches!(self.mode, Mode::Visual | Mode::VisualLine) {
+            return None;
+        }
+
+        let anchor = self.anchor;
+        let cursor = (self.cx, self.cy);
+        if (anchor.1, anchor.0) <= (cursor.1, cursor.0) {
+            Some((anchor, cursor))
+        } else {
+            Some((cursor, anchor))
+        }
+    }
+
+    fn selection_highlight(&self, y: usize, line: &str) -> Option<Range<usize>> {
+        let (start, end) = self.selection()?;
+        let range = VisualRange {
+            start,
+            end,
+            linewise: self.mode == Mode::VisualLine,
+        };
+        self.range_highlight(range, y, line)
+    }
+
+    /// The just-yanked text, highlighted briefly after `y` in visual mode.
+    fn yank_highlight(&self, y: usize, line: &str) -> Option<Range<usize>> {
+        let (range, _) = self
+            .yank_flash
+            .filter(|(_, time)| time.elapsed() < YANK_FLASH)?;
+        self.range_highlight(range, y, line)
+    }
+
+    fn extra_cursor_highlight(&self, y: usize, line: &str) -> Option<Range<usize>> {
+        let &(x, _) = self.extra_cursors.iter().find(|&&(_, cy)| cy == y)?;
+        let x = x.min(line.len());
+        // Past the end of the line there is no character to mark.
+        Some(x..self.current_buffer().char_end(x, y)).filter(|range| !range.is_empty())
+    }
+
+    fn range_highlight(&self, range: VisualRange, y: usize, line: &str) -> Option<Range<usize>> {
+        let ((sx, sy), (ex, ey)) = (range.start, range.end);
+        if y < sy || y > ey {
+            return None;
+        }
+
+        if range.linewise {
+            return Some(0..line.len());
+        }
+
+        let start = if y == sy { sx.min(line.len()) } else { 0 };
+        let end = if y == ey {
+            self.current_buffer().char_end(ex, ey)
+        } else {
+            line.len()
+        };
+        Some(start..end)
+    }
+
+    /// The bracket under the cursor and its match, if both exist.
+    fn bracket_pair(&self) -> Option<((usize, usize), (usize, usize))> {
+        let (cx, cy) = (self.cx, self.cy);
+        let buffer = self.current_buffer();
+        let c = buffer.get_line(cy).get(cx..)?.chars().next()?;
+        if !"()[]{}".contains(c) {
+            return None;
+        }
+        let top = self.scroll_offset;
+        let lines = top..top + self.visible_lines();
+        Some(((cx, cy), buffer.matching_bracket_within(cx, cy, lines)?))
+    }
+
+    fn confirm_highlight(&self, y: usize) -> Option<Range<usize>> {
+        let confirm = self.confirm.as_ref()?;
+        if y != self.cy {
+            return None;
+        }
+        let cx = self.cx;
+        Some(cx..cx + confirm.len)
+    }
+
+    fn preview_search(&mut self) {
+        let Some((ox, oy, scroll_offset)) = self.search_origin else {
+            return;
+        };
+
+        let found = self
+            .incremental_pattern()
+            .and_then(|pattern| self.current_buffer().find_forward(&pattern, ox, oy));
+        let (x, y) = found.unwrap_or((ox, oy));
+        self.cx = x;
+        self.cy = y;
+        self.scroll_offset = scroll_offset;
+    }
+
+    fn restore_search_origin(&mut self) {
+        if let Some((x, y, scroll_offset)) = self.search_origin.take() {
+            self.cx = x;
+            self.cy = y;
+            self.scroll_offset = scroll_offset;
+        }
+    }
+
+    fn search(&mut self, forward: bool) {
+        let Some(query) = self.last_search.clone() else {
+            self.set_error_message("No previous search pattern".to_string());
+            return;
+        };
+        self.search_highlighted = true;
+
+        let cx = self.cx;
+        let cy = self.cy;
+        let pattern = match self.search_pattern(&query) {
+            Ok(pattern) => pattern,
+            Err(e) => {
+                let reason = e.to_string();
+                let reason = reason.lines().last().unwrap_or_default();
+                self.set_error_message(format!("Invalid pattern: {}", reason));
+                return;
+            }
+        };
+        let found = if forward {
+            self.current_buffer().find_forward(&pattern, cx, cy)
+        } else {
+            self.current_buffer().find_backward(&pattern, cx, cy)
+        };
+
+        match found {
+            Some((x, y)) => {
+                self.cx = x;
+                self.cy = y;
+            }
+            None => self.set_error_message(format!("Pattern not found: {}", query)),
+        }
+    }
+
+    fn substitute(&mut self, command: &str, range: Option<VisualRange>) {
+        let sub = match command::parse_substitute(command) {
+            Ok(sub) => sub,
+            Err(e) => {
+                self.set_error_message(e.to_string());
+                return;
+            }
+        };
+
+        // An empty pattern reuses the last search, read the way `/` reads it.
+        let (query, pattern) = if sub.pattern.is_empty() {
+            match &self.last_search {
+                Some(query) => (query.clone(), self.search_pattern(query)),
+                None => {
+                    self.set_error_message("No previous search pattern".to_string());
+                    return;
+                }
+            }
+        } else {
+            let pattern = Regex::new(&regex::escape(&sub.pattern));
+            (sub.pattern, pattern)
+        };
+        let pattern = match pattern {
+            Ok(pattern) => pattern,
+            Err(e) => {
+                self.set_error_message(format!("Invalid pattern: {}", e));
+                return;
+            }
+        };
+
+        let lines = if sub.whole_buffer || range.is_some() {
+            self.line_range(range)
+        } else {
+            let cy = self.cy;
+            cy..cy + 1
+        };
+
+        if sub.confirm {
+            self.confirm = Some(Confirm {
+                query,
+                pattern,
+                len: 0,
+                replacement: sub.replacement,
+                global: sub.global,
+                end: lines.end,
+                total: 0,
+                changed_lines: 0,
+                line_changed: false,
+            });
+            self.mode = Mode::Confirm;
+            self.next_confirm_match(0, lines.start);
+            return;
+        }
+
+        let mut total = 0;
+        let mut changed_lines = 0;
+        for y in lines {
+            let count = match self.current_buffer_mut().substitute(
+                y,
+                &pattern,
+                &sub.replacement,
+                sub.global,
+            ) {
+                Ok(count) => count,
+                Err(e) => {
+                    self.set_error_message(e.to_string());
+                    return;
+                }
+            };
+            if count > 0 {
+                total += count;
+                changed_lines += 1;
+                self.cy = y;
+            }
+        }
+
+        self.report_substitutions(&query, total, changed_lines);
+    }
+
+    fn report_substitutions(&mut self, pattern: &str, total: usize, changed_lines: usize) {
+        if total == 0 {
+            self.set_error_message(format!("Pattern not found: {}", pattern));
+        } else {
+            self.set_status_message(format!(
+                "{} substitution{} on {} line{}",
+                total,
+                if total == 1 { "" } else { "s" },
+                changed_lines,
+                if changed_lines == 1 { "" } else { "s" },
+            ));
+        }
+    }
+
+    /// Moves the cursor to the next match at or after `(x, y)`, or ends the
+    /// substitution when there are none left.
+    fn next_confirm_match(&mut self, mut x: usize, mut y: usize) {
+        let Some(confirm) = &mut self.confirm else {
+            return;
+        };
+
+        let buffer = &self.buffers[self.active_buffer];
+        while y < confirm.end.min(buffer.len()) {
+            let line = buffer.get_line(y);
+            let found = (x <= line.len())
+                .then(|| confirm.pattern.find_at(&line, x))
+                .flatten();
+            if let Some(found) = found {
+                confirm.len = found.len();
+                self.cx = found.start();
+                self.cy = y;
+                return;
+            }
+            x = 0;
+            y += 1;
+            confirm.line_changed = false;
+        }
+
+        self.finish_confirm();
+    }
+
+    fn finish_confirm(&mut self) {
+        self.mode = Mode::Normal;
+        if let Some(confirm) = self.confirm.take() {
+            self.report_substitutions(&confirm.query, confirm.total, confirm.changed_lines);
+        }
+    }
+
+    fn confirm_replace(&mut self) -> Result<()> {
+        let (cx, cy) = (self.cx, self.cy);
+        let Some(confirm) = &mut self.confirm else {
+            return Ok(());
+        };
+
+        let buffer = &mut self.buffers[self.active_buffer];
+        buffer.remove_range((cx, cy), (cx + confirm.len, cy))?;
+        buffer.insert_str(cx, cy, &confirm.replacement)?;
+        confirm.total += 1;
+        if !confirm.line_changed {
+            confirm.line_changed = true;
+            confirm.changed_lines += 1;
+        }
+
+        let next = cx + confirm.replacement.len() + usize::from(confirm.len == 0);
+        self.skip_confirm_match(next);
+        Ok(())
+    }
+
+    /// Continues from column `x` of the current line, or from the next line
+    /// when only the first match on each line is being replaced.
+    fn skip_confirm_match(&mut self, x: usize) {
+        let cy = self.cy;
+        match &self.confirm {
+            Some(confirm) if confirm.global => self.next_confirm_match(x, cy),
+            Some(_) => self.next_confirm_match(usize::MAX, cy),
+            None => {}
+        }
+    }
+
+    fn handle_confirm_key(&mut self, key: KeyEvent) -> Result<()> {
+        match key.code {
+            KeyCode::Char('y') => self.confirm_replace()?,
+            KeyCode::Char('n') => {
+                let len = self.confirm.as_ref().map_or(0, |c| c.len.max(1));
+                self.skip_confirm_match(self.cx + len);
+            }
+            KeyCode::Char('a') => {
+                while self.confirm.is_some() {
+                    self.confirm_replace()?;
+                }
+            }
+            KeyCode::Char('q') | KeyCode::Esc => self.finish_confirm(),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn undo(&mut self, redo: bool) {
+        let cursor = (self.cx, self.cy);
+        let buffer = self.current_buffer_mut();
+        let restored = if redo {
+            buffer.redo(cursor)
+        } else {
+            buffer.undo(cursor)
+        };
+        match restored {
+            Some((cx, cy)) => {
+                self.cx = cx;
+                self.cy = cy;
+            }
+            None if redo => self.set_status_message("Already at newest change".to_string()),
+            None => self.set_status_message("Already at oldest change".to_string()),
+        }
+    }
+
+    fn push_jump(&mut self) {
+        self.jumps.truncate(self.jump_index);
+        self.jumps.push((self.active_buffer, self.cx, self.cy));
+        if self.jumps.len() > MAX_JUMPS {
+            self.jumps.remove(0);
+        }
+        self.jump_index = self.jumps.len();
+    }
+
+    /// Forgets the jumps into buffer `removed` and renumbers the rest to
+    /// match the buffers that remain.
+    fn remove_jumps(&mut self, removed: usize) {
+        let before = self.jumps[..self.jump_index.min(self.jumps.len())]
+            .iter()
+            .filter(|jump| jump.0 == removed)
+            .count();
+        self.jumps.retain(|jump| jump.0 != removed);
+        for jump in &mut self.jumps {
+            if jump.0 > removed {
+                jump.0 -= 1;
+            }
+        }
+        self.jump_index = (self.jump_index - before).min(self.jumps.len());
+    }
+
+    pub fn focus_buffer(&mut self, index: usize) {
+        if index < self.buffers.len() && index != self.active_buffer {
+            self.switch_buffer(index);
+        }
+    }
+
+    fn make_session(&mut self, path: &str, force: bool) {
+        self.current_buffer_mut().cursor = (self.cx, self.cy);
+
+        let mut session = Session {
+            active: 0,
+            buffers: Vec::new(),
+        };
+        for (i, buffer) in self.buffers.iter().enumerate() {
+            let Some(file) = &buffer.file else {
+                continue;
+            };
+            if i == self.active_buffer {
+                session.active = session.buffers.len();
+            }
+            session.buffers.push(SessionBuffer {
+                path: std::path::absolute(file).unwrap_or_else(|_| file.clone()),
+                cursor: buffer.cursor,
+            });
+        }
+
+        match session.save(path, force) {
+            Ok(()) => self.set_status_message(format!("Session saved to {}", path)),
+            Err(e) => self.set_error_message(format!("{:#}", e)),
+        }
+    }
+
+    /// Makes `index` the active buffer, remembering where the cursor was in
+    /// the current one and restoring the position last seen in the new one.
+    fn switch_buffer(&mut self, index: usize) {
+        let (cx, cy) = (self.cx, self.cy);
+        let scroll = (self.scroll_offset, self.left_col);
+        if let Some(buffer) = self.buffers.get_mut(self.active_buffer) {
+            buffer.cursor = (cx, cy);
+            buffer.scroll = scroll;
+        }
+
+        self.active_buffer = index;
+        self.restore_view();
+    }
+
+    fn restore_view(&mut self) {
+        let buffer = self.current_buffer();
+        let cy = buffer.cursor.1.min(buffer.len() - 1);
+        let cx = buffer.cursor.0.min(buffer.get_line(cy).len());
+        let (scroll_offset, left_col) = buffer.scroll;
+        self.cx = cx;
+        self.cy = cy;
+        self.scroll_offset = scroll_offset.min(cy);
+        self.left_col = left_col;
+        self.desired_cx = None;
+    }
+
+    fn go_to_jump(&mut self, (buffer, x, y): (usize, usize, usize)) {
+        if buffer < self.buffers.len() {
+            self.switch_buffer(buffer);
+            self.cx = x;
+            self.cy = y;
+        }
+    }
+
+    fn find_char(&mut self, find: Find, target: char, repeat: bool) {
+        let cx = self.cx;
+        let line = self.current_buffer().get_line(self.cy).into_owned();
+        // Repeating a till motion must step over the character it stopped before.
+        let skip = usize::from(repeat && matches!(find, Find::TillForward | Find::TillBackward));
+
+        let found = match find {
+            Find::Forward | Find::TillForward => line
+                .get(cx..)
+                .unwrap_or("")
+                .char_indices()
+                .skip(1 + skip)
+                .find(|&(_, c)| c == target)
+                .map(|(i, _)| cx + i),
+            Find::Backward | Find::TillBackward => line[..cx.min(line.len())]
+                .char_indices()
+                .rev()
+                .skip(skip)
+                .find(|&(_, c)| c == target)
+                .map(|(i, _)| i),
+        };
+
+        let Some(x) = found else {
+            return;
+        };
+        self.cx = match find {
+            Find::Forward | Find::Backward => x,
+            Find::TillForward => line[..x].char_indices().next_back().map_or(x, |(i, _)| i),
+            Find::TillBackward => x + target.len_utf8(),
+        };
+    }
+
+    fn set_option(&mut self, option: &str) {
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (option, None),
+        };
+
+        match (name, value) {
+            ("expandtab" | "et", None) => self.expand_tab = true,
+            ("noexpandtab" | "noet", None) => self.expand_tab = false,
+            ("number" | "nu", None) => self.number = true,
+            ("nonumber" | "nonu", None) => self.number = false,
+            ("autoindent" | "ai", None) => self.auto_indent = true,
+            ("noautoindent" | "noai", None) => self.auto_indent = false,
+            ("ignorecase" | "ic", None) => self.ignore_case = true,
+            ("noignorecase" | "noic", None) => self.ignore_case = false,
+            ("smartcase" | "scs", None) => self.smart_case = true,
+            ("nosmartcase" | "noscs", None) => self.smart_case = false,
+            ("colorcolumn" | "cc", Some(value)) => {
+                let columns: Result<Vec<usize>, _> = value
+                    .split(',')
+                    .filter(|column| !column.is_empty())
+                    .map(str::parse::<usize>)
+                    .collect();
+                match columns {
+                    Ok(columns) if !columns.contains(&0) => self.color_columns = columns,
+                    _ => self.set_error_message(format!("Invalid argument: {}", option)),
+                }
+            }
+            ("list", None) => self.list = true,
+            ("nolist", None) => self.list = false,
+            ("autosave", Some(value)) => match value.parse::<u64>() {
+                Ok(seconds) => {
+                    self.autosave = seconds;
+                    self.last_autosave = Instant::now();
+                }
+                Err(_) => self.set_error_message(format!("Invalid argument: {}", option)),
+            },
+            ("scrolloff" | "so", Some(value)) => match value.parse::<usize>() {
+                Ok(lines) => self.scroll_off = lines,
+                Err(_) => self.set_error_message(format!("Invalid argument: {}", option)),
+            },
+            ("autopairs", None) => self.auto_pairs = true,
+            ("noautopairs", None) => self.auto_pairs = false,
+            ("showtabline" | "stal", Some(value)) => match value.parse::<u8>() {
+                Ok(mode @ 0..=2) => self.show_tab_line = mode,
+                _ => self.set_error_message(format!("Invalid argument: {}", option)),
+            },
+            ("hlsearch" | "hls", None) => {
+                self.hl_search = true;
+                self.search_highlighted = true;
+            }
+            ("nohlsearch" | "nohls", None) => self.hl_search = false,
+            ("wrap", None) => self.wrap = true,
+            ("nowrap", None) => self.wrap = false,
+            ("fixendofline" | "fixeol", None) => self.fix_end_of_line = true,
+            ("nofixendofline" | "nofixeol", None) => self.fix_end_of_line = false,
+            ("backup" | "bk", None) => self.backup = true,
+            ("nobackup" | "nobk", None) => self.backup = false,
+            ("fileformat" | "ff", None) => {
+                let format = match self.current_buffer().line_ending {
+                    LineEnding::Unix => "unix",
+                    LineEnding::Dos => "dos",
+                };
+                self.set_status_message(format!("fileformat={}", format));
+            }
+            ("fileformat" | "ff", Some(value)) => {
+                let line_ending = match value {
+                    "unix" => LineEnding::Unix,
+                    "dos" => LineEnding::Dos,
+                    _ => {
+                        self.set_error_message(format!("Invalid argument: {}", option));
+                        return;
+                    }
+                };
+                let buffer = self.current_buffer_mut();
+                if buffer.line_ending != line_ending {
+                    buffer.line_ending = line_ending;
+                    buffer.is_modified = true;
+                }
+            }
+            ("tabstop" | "ts", Some(value)) => match value.parse::<usize>() {
+                Ok(width) if width > 0 => self.tab_width = width,
+                _ => self.set_error_message(format!("Invalid argument: {}", option)),
+            },
+            _ => self.set_error_message(format!("Unknown option: {}", option)),
+        }
+        self.redraw = true;
+    }
+
+    fn set_colorscheme(&mut self, name: Option<&str>) {
+        let available = || {
+            self.theme_set
+                .themes
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        match name {
+            Some(name) => match self.theme_set.themes.get(name) {
+                Some(theme) => {
+                    self.theme = theme.clone();
+                    self.highlight_caches.clear();
+                    self.redraw = true;
+                }
+                None => {
+                    let msg = format!("Unknown colorscheme: {} (available: {})", name, available());
+                    self.set_error_message(msg);
+                }
+            },
+            None => {
+                let msg = format!("Available colorschemes: {}", available());
+                self.set_status_message(msg);
+            }
+        }
+    }
+
+    fn move_to_line(&mut self, y: usize) {
+        let col = self.desired_cx.unwrap_or_else(|| {
+            self.current_buffer()
+                .display_column(self.cx, self.cy, self.tab_width)
+        });
+        self.desired_cx = Some(col);
+        self.cy = y;
+        self.cx = self
+            .current_buffer()
+            .column_to_index(col, y, self.tab_width);
+    }
+
+    fn quit(&mut self) -> Result<()> {
+        let unsaved: Vec<String> = self
+            .buffers
+            .iter()
+            .filter(|b| b.is_modified)
+            .map(|b| b.file_name().unwrap_or_else(|| "[No Name]".to_string()))
+            .collect();
+
+        if unsaved.is_empty() {
+            self.handle_action(Action::Quit)?;
+        } else {
+            self.set_error_message(format!(
+                "No write since last change for {} (add ! to override)",
+                unsaved.join(", ")
+            ));
+        }
+        Ok(())
+    }
+
+    fn word_count(&mut self, range: Option<VisualRange>) {
+        let buffer = self.current_buffer();
+        let last = buffer.len() - 1;
+        let (start, end) = match range {
+            Some(range) if range.linewise => (
+                (0, range.start.1),
+                (buffer.get_line(range.end.1).len(), range.end.1),
+            ),
+            Some(range) => (
+                range.start,
+                (buffer.char_end(range.end.0, range.end.1), range.end.1),
+            ),
+            None => ((0, 0), (buffer.get_line(last).len(), last)),
+        };
+
+        let text = buffer.text_range(start, end);
+        let lines = end.1 - start.1 + 1;
+        let words = text.split_whitespace().count();
+        let chars = text.chars().count();
+        let counts = format!(
+            "{} line{}, {} word{}, {} char{}",
+            lines,
+            if lines == 1 { "" } else { "s" },
+            words,
+            if words == 1 { "" } else { "s" },
+            chars,
+            if chars == 1 { "" } else { "s" }
+        );
+
+        let msg = if range.is_some() {
+            format!("Selected {}", counts)
+        } else {
+            let name = buffer
+                .file_name()
+                .unwrap_or_else(|| "[No Name]".to_string());
+            let modified = if buffer.is_modified { " [+]" } else { "" };
+            format!("\"{}\"{} {}", name, modified, counts)
+        };
+        self.set_status_message(msg);
+    }
+
+    fn line_range(&self, range: Option<VisualRange>) -> Range<usize> {
+        match range {
+            Some(range) => range.start.1..range.end.1 + 1,
+            None => 0..self.current_buffer().len(),
+        }
+    }
+
+    fn sort_lines(
+        &mut self,
+        range: Option<VisualRange>,
+        reverse: bool,
+        numeric: bool,
+    ) -> Result<()> {
+        let lines = self.line_range(range);
+        let mut sorted = self.current_buffer().get_lines(lines.clone());
+        if numeric {
+            sorted.sort_by_key(|line| leading_number(line));
+        } else {
+            sorted.sort();
+        }
+        if reverse {
+            sorted.reverse();
+        }
+
+        if sorted != self.current_buffer().get_lines(lines.clone()) {
+            self.current_buffer_mut()
+                .replace_lines(lines.clone(), sorted)?;
+        }
+        self.cy = lines.start;
+        self.cx = 0;
+        Ok(())
+    }
+
+    fn filter_lines(&mut self, range: Option<VisualRange>, shell: &str) -> Result<()> {
+        if shell.is_empty() {
+            self.set_error_message("Argument required".to_string());
+            return Ok(());
+        }
+
+        let lines = self.line_range(range);
+        let mut input = self.current_buffer().get_lines(lines.clone()).join("\n");
+        input.push('\n');
+        let output = match command::run_shell(shell, Some(input)) {
+            Ok(output) => output,
+            Err(e) => {
+                self.set_error_message(format!("{}: {}", shell, e));
+                return Ok(());
+            }
+        };
+
+        let filtered: Vec<String> = output.lines().map(String::from).collect();
+        let count = lines.len();
+        self.current_buffer_mut()
+            .replace_lines(lines.clone(), filtered)?;
+        self.cy = lines.start.min(self.current_buffer().len() - 1);
+        self.cx = 0;
+        self.set_status_message(format!(
+            "{} line{} filtered",
+            count,
+            if count == 1 { "" } else { "s" }
+        ));
+        Ok(())
+    }
+
+    fn trim_whitespace(&mut self, range: Option<VisualRange>) -> Result<()> {
+        let mut count = 0;
+        for cy in self.line_range(range) {
+            let line = self.current_buffer().get_line(cy);
+            let len = line.len();
+            let trimmed = line.trim_end_matches([' ', '\t']).len();
+            if trimmed < len {
+                self.current_buffer_mut()
+                    .remove_range((trimmed, cy), (len, cy))?;
+                count += 1;
+            }
+        }
+        self.adjust_cursor_position();
+        self.set_status_message(format!(
+            "{} line{} trimmed",
+            count,
+            if count == 1 { "" } else { "s" }
+        ));
+        Ok(())
+    }
+
+    fn read_into_buffer(&mut self, arg: &str) -> Result<()> {
+        let text = match arg.strip_prefix('!') {
+            Some(shell) => command::run_shell(shell.trim(), None)
+                .map_err(|e| anyhow::anyhow!("{}: {}", shell.trim(), e)),
+            None if arg.is_empty() => Err(anyhow::anyhow!("Argument required")),
+            None => std::fs::read_to_string(arg)
+                .map_err(|e| anyhow::anyhow!("Can't read {}: {}", arg, e)),
+        };
+        let text = match text {
+            Ok(text) => text,
+            Err(e) => {
+                self.set_error_message(e.to_string());
+                return Ok(());
+            }
+        };
+
+        let lines: Vec<String> = text.lines().map(String::from).collect();
+        if lines.is_empty() {
+            return Ok(());
+        }
+        let cy = self.cy + 1;
+        self.current_buffer_mut().insert_lines(cy, lines)?;
+        self.cy = cy;
+        self.cx = 0;
+        Ok(())
+    }
+
+    /// Runs each `|`-separated command in turn, stopping at the first one
+    /// that fails.
+    fn run_commands(&mut self, line: &str) -> Result<()> {
+        let commands = command::split_commands(line);
+        let chained = commands.len() > 1;
+        for command in commands {
+            self.command_failed = false;
+            self.handle_command(&command)?;
+            if self.command_failed {
+                if let Some((msg, time)) = self.status_message.as_mut().filter(|_| chained) {
+                    *msg = format!(":{}: {}", command, msg);
+                    *time = Instant::now();
+                }
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_command(&mut self, command: &str) -> Result<()> {
+        let (range, command) = match command.strip_prefix("'<,'>") {
+            Some(rest) => (self.visual_range.take(), rest.trim_start()),
+            None => (None, command),
+        };
+
+        if let Some(shell) = command.strip_prefix('!') {
+            self.filter_lines(range, shell.trim())?;
+            return Ok(());
+        }
+
+        if command::is_substitute(command) {
+            self.substitute(command, range);
+            return Ok(());
+        }
+
+        let parts: Vec<&str> = command.split_whitespace().collect();
+        if parts.is_empty() {
+            return Ok(());
+        }
+
+        match parts[0] {
+            "w" | "write" | "w!" | "write!" => {
+                if parts.len() > 1 {
+                    let force = parts[0].ends_with('!');
+                    self.handle_action(Action::SaveAs(parts[1].to_string(), force))?;
+                } else {
+                    self.handle_action(Action::Save(parts[0].ends_with('!')))?;
+                }
+            }
+            "q" | "quit" if self.windows.len() > 1 => self.close_window(),
+            "q!" | "quit!" if self.windows.len() > 1 => self.close_window(),
+            "q" | "quit" | "qa" | "qall" => self.quit()?,
+            "q!" | "quit!" | "qa!" | "qall!" => {
+                self.handle_action(Action::Quit)?;
+            }
+            "sp" | "split" => {
+                self.handle_action(Action::SplitWindow)?;
+                if let Some(path) = parts.get(1) {
+                    self.handle_action(Action::OpenFile(path.to_string()))?;
+                }
+            }
+            "clo" | "close" => self.handle_action(Action::CloseWindow)?,
+            "on" | "only" => self.handle_action(Action::OnlyWindow)?,
+            "set" => {
+                for option in &parts[1..] {
+                    self.set_option(option);
+                }
+            }
+            "e" | "edit" => match parts.get(1) {
+                Some(path) => self.handle_action(Action::OpenFile(path.to_string()))?,
+                None => self.handle_action(Action::Reload(false))?,
+            },
+            "e!" | "edit!" => match parts.get(1) {
+                Some(path) => self.handle_action(Action::OpenFile(path.to_string()))?,
+                None => self.handle_action(Action::Reload(true))?,
+            },
+            "bn" | "bnext" => self.handle_action(Action::NextBuffer)?,
+            "bp" | "bprevious" | "bN" | "bNext" => self.handle_action(Action::PreviousBuffer)?,
+            "bn!" | "bnext!" => self.handle_action(Action::NextModifiedBuffer(true))?,
+            "bp!" | "bprevious!" | "bN!" | "bNext!" => {
+                self.handle_action(Action::NextModifiedBuffer(false))?
+            }
+            "bd" | "bdelete" => self.handle_action(Action::CloseBuffer(false))?,
+            "bd!" | "bdelete!" => self.handle_action(Action::CloseBuffer(true))?,
+            "colo" | "colorscheme" => {
+                self.set_colorscheme(parts.get(1).copied());
+            }
+            "sor" | "sort" | "sor!" | "sort!" => {
+                let numeric = parts[1..].contains(&"n");
+                self.sort_lines(range, parts[0].ends_with('!'), numeric)?;
+            }
+            "r" | "read" => {
+                let arg = command
+                    .split_once(char::is_whitespace)
+                    .map_or("", |(_, arg)| arg.trim());
+                self.read_into_buffer(arg)?;
+            }
+            "mks" | "mksession" | "mks!" | "mksession!" => {
+                let path = parts.get(1).copied().unwrap_or(session::DEFAULT_PATH);
+                self.make_session(path, parts[0].ends_with('!'));
+            }
+            "noh" | "nohlsearch" => self.search_highlighted = false,
+            "so" | "source" => self.source_config(parts.get(1).copied()),
+            "wc" => self.word_count(range),
+            "TrimWhitespace" => self.trim_whitespace(range)?,
+            "wq" => {
+                self.handle_action(Action::Save(false))?;
+                // Keep the save error on screen rather than the unsaved warning.
+                if !self.current_buffer().is_modified {
+                    self.quit()?;
+                }
+            }
+            _ => {
+                self.set_error_message(format!("Unknown command: {}", command));
+            }
+        }
+        Ok(())
+    }
+
+    fn view_state(&self) -> ViewState {
+        ViewState {
+            buffer: self.active_buffer,
+            version: self.current_buffer().version(),
+            scroll_offset: self.scroll_offset,
+            left_col: self.left_col,
+            tab_line: self.tab_line_height() > 0,
+            size: self.size,
+            mode: self.mode,
+            selection: self.selection(),
+            search: (self.mode == Mode::Search).then(|| self.command_line.clone()),
+            hl_search: self.hl_search_query().map(str::to_string),
+            confirm: self.confirm.as_ref().map(|_| (self.cx, self.cy)),
+            brackets: self.brackets,
+            extra_cursors: self.extra_cursors.clone(),
+            yank_flash: self
+                .yank_flash
+                .is_some_and(|(_, time)| time.elapsed() < YANK_FLASH),
+            windows: (0..self.windows.len())
+                .map(|i| {
+                    let window = if i == self.active_window {
+                        self.current_window()
+                    } else {
+                        self.windows[i]
+                    };
+                    let version = self.buffers.get(window.buffer).map_or(0, Buffer::version);
+                    (
+                        window.buffer,
+                        window.scroll_offset,
+                        window.left_col,
+                        version,
+                    )
+                })
+                .collect(),
+            active_window: self.active_window,
+        }
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        let mut stdout = stdout();
+        loop {
+            self.draw(&mut stdout)?;
+            if self.exit {
+                break;
+            }
+
+            let interval = if self.buffers.iter().any(Buffer::is_loading) {
+                LOAD_INTERVAL
+            } else {
+                POLL_INTERVAL
+            };
+            if poll(interval)? {
+                match read()? {
+                    Event::Key(key) => {
+                        if let Err(e) = self.handle_key(key) {
+                            self.set_error_message(e.to_string());
+                        }
+                    }
+                    Event::Resize(width, height) => {
+                        self.size = (width, height);
+                        self.screen.resize(self.size);
+                        self.drawn_status = None;
+                        self.adjust_scroll();
+                    }
+                    _ => {}
+                }
+            }
+            self.load_buffers();
+            self.autosave();
+        }
+        Ok(())
+    }
+
+    /// Adds whatever has been read of files still loading in the background.
+    fn load_buffers(&mut self) {
+        let mut errors = Vec::new();
+        for buffer in &mut self.buffers {
+            if let Err(e) = buffer.load_more() {
+                errors.push(format!("{:#}", e));
+            }
+        }
+        if let Some(error) = errors.pop() {
+            self.set_error_message(error);
+        }
+    }
+
+    /// Renders the current state, writing only the cells that changed.
+    pub fn draw(&mut self, out: &mut impl Write) -> Result<()> {
+        self.adjust_cursor_position();
+
+        self.brackets = self.bracket_pair();
+        let view = self.view_state();
+        if self.redraw || self.drawn_view.as_ref() != Some(&view) {
+            self.draw_windows();
+            self.drawn_view = Some(view);
+            self.redraw = false;
+        }
+        self.draw_tab_line();
+        self.draw_status_line();
+        self.screen.flush(out)?;
+        let (x, y) = self.cursor_position();
+        out.queue(cursor::MoveTo(x, y))?;
+        out.flush()?;
+        Ok(())
+    }
+
+    fn autosave(&mut self) {
+        if self.autosave == 0 || self.last_autosave.elapsed().as_secs() < self.autosave {
+            return;
+        }
+        self.last_autosave = Instant::now();
+
+        let fix_end_of_line = self.fix_end_of_line;
+        let backup = self.backup;
+        let mut saved = 0;
+        let mut errors = Vec::new();
+        for buffer in &mut self.buffers {
+            if !buffer.is_modified || buffer.file.is_none() || buffer.binary {
+                continue;
+            }
+            buffer.end_of_line |= fix_end_of_line;
+            match buffer.save(false, backup) {
+                Ok(()) => saved += 1,
+                Err(e) => errors.push(format!("{:#}", e)),
+            }
+        }
+
+        if let Some(error) = errors.first() {
+            self.set_status_message(format!("Autosave failed: {}", error));
+        } else if saved > 0 {
+            self.set_status_message(format!(
+                "Autosaved {} buffer{}",
+                saved,
+                if saved == 1 { "" } else { "s" }
+            ));
+        }
+    }
+
+    /// Whether `key` in normal mode begins a change for `.` to repeat: either
+    /// a bound editing action or one of the built-in `r`, `d`, `c` and `]`.
+    fn starts_change(&self, key: KeyEvent) -> bool {
+        match self.normal_keys.get(&keymap::key_of(key)) {
+            Some(action) => is_change(action),
+            None => {
+                !key.modifiers.contains(KeyModifiers::CONTROL)
+                    && matches!(key.code, KeyCode::Char('r' | 'd' | 'c' | ']'))
+            }
+        }
+    }
+
+    pub fn handle_key(&mut self, key: KeyEvent) -> Result<()> {
+        if self.mode == Mode::Normal && self.pending.is_none() {
+            if self.starts_change(key) {
+                self.recording = Some(Change {
+                    count: self.count,
+                    keys: Vec::new(),
+                });
+            }
+            // Everything up to the next return to normal mode, such as a
+            // whole insert, is undone together.
+            let cursor = (self.cx, self.cy);
+            self.current_buffer_mut().begin_change(cursor);
+        }
+        if let Some(change) = &mut self.recording {
+            change.keys.push(key);
+        }
+
+        match self.mode {
+            Mode::Normal => self.handle_normal_key(key)?,
+            Mode::Insert => self.handle_insert_key(key)?,
+            Mode::Replace => self.handle_replace_key(key)?,
+            Mode::Visual | Mode::VisualLine => self.handle_visual_key(key)?,
+            Mode::Command | Mode::Search | Mode::SaveAs => self.handle_command_key(key)?,
+            Mode::Confirm => self.handle_confirm_key(key)?,
+        }
+
+        if self.mode == Mode::Normal && self.pending.is_none() {
+            if let Some(change) = self.recording.take() {
+                self.last_change = change;
+            }
+            self.current_buffer_mut().end_change();
+        }
+        Ok(())
+    }
+
+    fn handle_normal_key(&mut self, key: KeyEvent) -> Result<()> {
+        // A count after an operator, as in `d3w`, multiplies the one before it.
+        if let (Some(Pending::Operator(_)), KeyCode::Char(c @ '0'..='9')) = (self.pending, key.code)
+        {
+            if c != '0' || self.operator_count.is_some() {
+                let digit = c.to_digit(10).unwrap_or(0) as usize;
+                self.operator_count =
+                    Some(self.operator_count.unwrap_or(0).saturating_mul(10) + digit);
+                return Ok(());
+            }
+        }
+
+        if let Some(pending) = self.pending.take() {
+            if let Some(count) = self.operator_count.take() {
+                self.count = Some(self.count.unwrap_or(1).saturating_mul(count));
+            }
+            let action = self.pending_action(pending, key);
+            return self.handle_counted_action(action);
+        }
+
+        // `nop` turns off keys handled below as well as those in the keymap.
+        if let Some(Action::Nop) = self.normal_keys.get(&keymap::key_of(key)) {
+            self.count = None;
+            return Ok(());
+        }
+
+        if let KeyCode::Char(c @ '0'..='9') = key.code {
+            if c != '0' || self.count.is_some() {
+                let digit = c.to_digit(10).unwrap_or(0) as usize;
+                self.count = Some(self.count.unwrap_or(0).saturating_mul(10) + digit);
+                return Ok(());
+            }
+        }
+
+        // With a count, `%` goes to that percentage of the file instead of
+        // the matching bracket.
+        if key.code == KeyCode::Char('%') {
+            if let Some(percent) = self.count.take() {
+                return self.handle_action(Action::GoToPercent(percent));
+            }
+        }
+        if key.code == KeyCode::Char('G') {
+            let line = self.count.take();
+            return self.handle_action(Action::GoToLine(line));
+        }
+
+        if let Some(action) = self.normal_keys.get(&keymap::key_of(key)) {
+            return self.handle_counted_action(Some(action.clone()));
+        }
+
+        let action = match (key.code, key.modifiers) {
+            (KeyCode::Char('w'), KeyModifiers::CONTROL) => {
+                self.pending = Some(Pending::Window);
+                None
+            }
+            (_, KeyModifiers::CONTROL) => motion_action(key),
+            (KeyCode::Char('r'), _) => {
+                self.pending = Some(Pending::Replace);
+                None
+            }
+            (KeyCode::Char('d'), _) => {
+                self.pending = Some(Pending::Operator(Operator::Delete));
+                None
+            }
+            (KeyCode::Char('c'), _) => {
+                self.pending = Some(Pending::Operator(Operator::Change));
+                None
+            }
+            (KeyCode::Char(c @ ('f' | 'F' | 't' | 'T')), _) => {
+                let find = match c {
+                    'f' => Find::Forward,
+                    'F' => Find::Backward,
+                    't' => Find::TillForward,
+                    _ => Find::TillBackward,
+                };
+                self.pending = Some(Pending::Find(find));
+                None
+            }
+            (KeyCode::Char('g'), _) => {
+                self.pending = Some(Pending::G);
+                None
+            }
+            (KeyCode::Char('z'), _) => {
+                self.pending = Some(Pending::Z);
+                None
+            }
+            (KeyCode::Char(']'), _) => {
+                self.pending = Some(Pending::RightBracket);
+                None
+            }
+            (KeyCode::Char('m'), _) => {
+                self.pending = Some(Pending::SetMark);
+                None
+            }
+            (KeyCode::Char(c @ ('`' | '\'')), _) => {
+                self.pending = Some(Pending::JumpToMark(c == '`'));
+                None
+            }
+            _ => motion_action(key),
+        };
+
+        if action.is_none() && self.pending.is_some() {
+            return Ok(());
+        }
+        self.handle_counted_action(action)
+    }
+
+    fn pending_action(&mut self, pending: Pending, key: KeyEvent) -> Option<Action> {
+        let KeyCode::Char(c) = key.code else {
+            return None;
+        };
+
+        match pending {
+            Pending::Replace => Some(Action::ReplaceUnderCursor(c)),
+            Pending::Operator(op) => {
+                let motion = match (op, c) {
+                    (Operator::Delete, 'd') => Motion::Line,
+                    // A count on a change goes to its motion, since `c` can
+                    // only be done once before insert mode takes over.
+                    (Operator::Change, 'c') => match self.count.take() {
+                        Some(count) if count > 1 => Motion::LinesDown(count - 1),
+                        _ => Motion::Line,
+                    },
+                    (Operator::Change, 'w') => Motion::WordEnd(self.count.take().unwrap_or(1)),
+                    (_, 'w') => Motion::WordForward,
+                    (_, '$') => Motion::EndOfLine,
+                    (_, '0') => Motion::StartOfLine,
+                    (_, 'j') => Motion::LinesDown(self.count.take().unwrap_or(1)),
+                    (_, 'k') => Motion::LinesUp(self.count.take().unwrap_or(1)),
+                    _ => return None,
+                };
+                Some(Action::Operate(op, motion))
+            }
+            Pending::Find(find) => Some(Action::FindChar(find, c)),
+            Pending::SetMark => Some(Action::SetMark(c)),
+            Pending::G if c == 'c' => {
+                self.pending = Some(Pending::Comment);
+                None
+            }
+            Pending::G if c == 'g' => Some(Action::GoToLine(Some(self.count.take().unwrap_or(1)))),
+            Pending::G => None,
+            Pending::Comment if c == 'c' => Some(Action::ToggleComment),
+            Pending::Comment => None,
+            Pending::Z => match c {
+                't' => Some(Action::ScrollCursorTo(ScrollAnchor::Top)),
+                'z' => Some(Action::ScrollCursorTo(ScrollAnchor::Center)),
+                'b' => Some(Action::ScrollCursorTo(ScrollAnchor::Bottom)),
+                _ => None,
+            },
+            Pending::RightBracket => match c {
+                'p' => Some(Action::PasteIndented),
+                _ => None,
+            },
+            Pending::JumpToMark(exact) => Some(Action::JumpToMark(c, exact)),
+            Pending::Window => {
+                let (active, count) = (self.active_window, self.windows.len());
+                match c {
+                    'w' => Some(Action::FocusWindow((active + 1) % count)),
+                    'W' | 'p' => Some(Action::FocusWindow((active + count - 1) % count)),
+                    'j' => Some(Action::FocusWindow((active + 1).min(count - 1))),
+                    'k' => Some(Action::FocusWindow(active.saturating_sub(1))),
+                    's' | 'S' => Some(Action::SplitWindow),
+                    'c' | 'q' => Some(Action::CloseWindow),
+                    'o' => Some(Action::OnlyWindow),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    /// Replays the last change. Like vim, a count replaces the one the
+    /// change was made with.
+    fn repeat_change(&mut self, count: Option<usize>) -> Result<()> {
+        let change = self.last_change.clone();
+        self.count = count.or(change.count);
+        for key in change.keys {
+            self.handle_key(key)?;
+        }
+        Ok(())
+    }
+
+    fn operate_lines(&mut self, op: Operator, lines: Range<usize>) -> Result<()> {
+        let start = lines.start;
+        let removed = self.current_buffer_mut().remove_lines(lines)?;
+        self.register = Some(RegisterContent::Lines(removed));
+        if op == Operator::Change {
+            self.current_buffer_mut()
+                .insert_lines(start, vec![String::new()])?;
+            self.mode = Mode::Insert;
+        }
+        let cy = start.min(self.current_buffer().len() - 1);
+        self.cy = cy;
+        self.cx = self.current_buffer().indentation(cy).len();
+        Ok(())
+    }
+
+    fn handle_counted_action(&mut self, action: Option<Action>) -> Result<()> {
+        let given = self.count.take();
+        let count = given.unwrap_or(1);
+        let Some(action) = action else {
+            return Ok(());
+        };
+        if let Action::RepeatChange = action {
+            return self.repeat_change(given);
+        }
+
+        let repeats = !matches!(
+            action,
+            Action::EnterMode(_)
+                | Action::Append
+                | Action::AppendEndOfLine
+                | Action::OpenLineBelow
+                | Action::OpenLineAbove
+                | Action::Operate(Operator::Change, _)
+        );
+        if count > 1
+            && matches!(
+                action,
+                Action::EnterMode(Mode::Insert)
+                    | Action::Append
+                    | Action::AppendEndOfLine
+                    | Action::OpenLineBelow
+                    | Action::OpenLineAbove
+            )
+        {
+            // The typed text is inserted count - 1 more times on Esc; `o` and
+            // `O` open a fresh line for each copy.
+            self.insert_repeat = count - 1;
+            self.inserted = match action {
+                Action::OpenLineBelow | Action::OpenLineAbove => {
+                    vec![KeyEvent::from(KeyCode::Enter)]
+                }
+                _ => Vec::new(),
+            };
+        }
+        if count == 1 || !repeats {
+            return self.handle_action(action);
+        }
+
+        // Repeated deletes collect everything they removed into one register entry.
+        let deletes = matches!(
+            action,
+            Action::DeleteUnderCursor
+                | Action::DeleteBeforeCursor
+                | Action::DeleteLine
+                | Action::Operate(Operator::Delete, _)
+        );
+        let previous = if deletes { self.register.take() } else { None };
+        let mut removed: Option<RegisterContent> = None;
+
+        for _ in 0..count {
+            // A count larger than the lines left deletes to the end of the buffer.
+            let last_line = matches!(
+                action,
+                Action::DeleteLine | Action::Operate(Operator::Delete, Motion::Line)
+            ) && self.cy + 1 >= self.current_buffer().len();
+            self.handle_action(action.clone())?;
+            self.adjust_cursor_position();
+
+            if deletes {
+                removed = match (removed, self.register.take()) {
+                    (
+                        Some(RegisterContent::Lines(mut lines)),
+                        Some(RegisterContent::Lines(more)),
+                    ) => {
+                        lines.extend(more);
+                        Some(RegisterContent::Lines(lines))
+                    }
+                    (Some(RegisterContent::Chars(text)), Some(RegisterContent::Chars(more))) => {
+                        Some(RegisterContent::Chars(
+                            if matches!(action, Action::DeleteBeforeCursor) {
+                                more + &text
+                            } else {
+                                text + &more
+                            },
+                        ))
+                    }
+                    (removed, None) => removed,
+                    (_, more) => more,
+                };
+            }
+            if last_line {
+                break;
+            }
+        }
+
+        if deletes {
+            self.register = removed.or(previous);
+        }
+        Ok(())
+    }
+
+    fn handle_visual_key(&mut self, key: KeyEvent) -> Result<()> {
+        let action = match (key.code, key.modifiers) {
+            (KeyCode::Char('g'), KeyModifiers::CONTROL) => Some(Action::WordCount),
+            (_, KeyModifiers::CONTROL) => motion_action(key),
+            (KeyCode::Esc, _) => Some(Action::EnterMode(Mode::Normal)),
+            (KeyCode::Char(':'), _) => Some(Action::EnterMode(Mode::Command)),
+            (KeyCode::Char('v'), _) if self.mode == Mode::Visual => {
+                Some(Action::EnterMode(Mode::Normal))
+            }
+            (KeyCode::Char('V'), _) if self.mode == Mode::VisualLine => {
+                Some(Action::EnterMode(Mode::Normal))
+            }
+            (KeyCode::Char('v'), _) => Some(Action::EnterMode(Mode::Visual)),
+            (KeyCode::Char('V'), _) => Some(Action::EnterMode(Mode::VisualLine)),
+            (KeyCode::Char('d' | 'x'), _) => Some(Action::DeleteSelection),
+            (KeyCode::Char('y'), _) => Some(Action::YankSelection),
+            (KeyCode::Char('~'), _) => Some(Action::ToggleCaseSelection),
+            _ => motion_action(key),
+        };
+
+        if let Some(action) = action {
+            self.handle_action(action)?;
+        }
+        Ok(())
+    }
+
+    fn handle_insert_key(&mut self, key: KeyEvent) -> Result<()> {
+        if let Some(code) = self.literal.take() {
+            if self.insert_literal(code, key)? {
+                if self.insert_repeat > 0 {
+                    self.inserted.push(key);
+                }
+                return Ok(());
+            }
+        }
+        if let Some(first) = self.digraph.take() {
+            if self.insert_repeat > 0 {
+                self.inserted.push(key);
+            }
+            // Any other key cancels the digraph.
+            if let KeyCode::Char(c) = key.code {
+                match first {
+                    None => self.digraph = Some(Some(c)),
+                    Some(first) => {
+                        let c = digraph::lookup(first, c).unwrap_or(c);
+                        self.handle_action(Action::AddChar(c))?;
+                    }
+                }
+            }
+            return Ok(());
+        }
+        if let Some(action) = self.insert_keys.get(&keymap::key_of(key)).cloned() {
+            if matches!(action, Action::EnterMode(Mode::Normal)) {
+                let keys = std::mem::take(&mut self.inserted);
+                for _ in 0..std::mem::take(&mut self.insert_repeat) {
+                    for &key in &keys {
+                        self.handle_insert_key(key)?;
+                    }
+                }
+            } else if self.insert_repeat > 0 {
+                self.inserted.push(key);
+            }
+            return self.handle_action(action);
+        }
+        if self.insert_repeat > 0 {
+            self.inserted.push(key);
+        }
+
+        match key.code {
+            KeyCode::Backspace if self.cx > 0 => {
+                let (cx, cy) = (self.cx, self.cy);
+                let line = self.current_buffer().get_line(cy);
+                let prefix = line.get(..cx).unwrap_or_default();
+                // In leading spaces, delete back to the previous tab stop.
+                let count =
+                    if self.expand_tab && !prefix.is_empty() && prefix.trim_start().is_empty() {
+                        let col = self.current_buffer().display_column(cx, cy, self.tab_width);
+                        let spaces = prefix.len() - prefix.trim_end_matches(' ').len();
+                        ((col - 1) % self.tab_width + 1).min(spaces).max(1)
+                    } else {
+                        1
+                    };
+                for _ in 0..count {
+                    self.handle_action(Action::DeleteChar)?;
+                }
+            }
+            KeyCode::Tab if self.expand_tab => {
+                let col = self
+                    .current_buffer()
+                    .display_column(self.cx, self.cy, self.tab_width);
+                for _ in 0..self.tab_width - col % self.tab_width {
+                    self.handle_action(Action::AddChar(' '))?;
+                }
+            }
+            KeyCode::Tab => self.handle_action(Action::AddChar('\t'))?,
+            KeyCode::Char(c) => self.handle_action(Action::AddChar(c))?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Inserts the key typed after Ctrl-V as is, or builds up a `u1234` or
+    /// `U0001f600` code point. Returns false when the key only ended a code
+    /// and still needs handling.
+    fn insert_literal(&mut self, mut code: String, key: KeyEvent) -> Result<bool> {
+        if code.is_empty() {
+            let c = match key.code {
+                KeyCode::Char(c @ ('u' | 'U'))
+                    if !key.modifiers.contains(KeyModifiers::CONTROL) =>
+                {
+                    self.literal = Some(c.to_string());
+                    return Ok(true);
+                }
+                KeyCode::Char(c) if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    control_char(c)
+                }
+                KeyCode::Char(c) => c,
+                KeyCode::Esc => '\x1b',
+                KeyCode::Enter => '\r',
+                KeyCode::Tab => '\t',
+                KeyCode::Backspace => '\x7f',
+                _ => return Ok(true),
+            };
+            self.handle_action(Action::AddChar(c))?;
+            return Ok(true);
+        }
+
+        let max_digits = if code.starts_with('U') { 8 } else { 4 };
+        let digit = match key.code {
+            KeyCode::Char(c) if c.is_ascii_hexdigit() => Some(c),
+            _ => None,
+        };
+        if let Some(digit) = digit {
+            code.push(digit);
+            if code.len() - 1 < max_digits {
+                self.literal = Some(code);
+                return Ok(true);
+            }
+        }
+
+        let c = match u32::from_str_radix(&code[1..], 16) {
+            Ok(value) => char::from_u32(value),
+            Err(_) => code.chars().next(),
+        };
+        match c {
+            Some(c) => self.handle_action(Action::AddChar(c))?,
+            None => self.set_error_message(format!("Invalid character code: {}", &code[1..])),
+        }
+        Ok(digit.is_some())
+    }
+
+    fn handle_replace_key(&mut self, key: KeyEvent) -> Result<()> {
+        match key.code {
+            KeyCode::Esc => self.handle_action(Action::EnterMode(Mode::Normal))?,
+            KeyCode::Enter => self.handle_action(Action::NewLine)?,
+            KeyCode::Backspace => self.handle_action(Action::MoveLeft)?,
+            KeyCode::Char(c) => self.handle_action(Action::ReplaceChar(c))?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_command_key(&mut self, key: KeyEvent) -> Result<()> {
+        if key.code != KeyCode::Tab {
+            self.completion = None;
+        }
+
+        match key.code {
+            KeyCode::Esc => {
+                self.command_line.clear();
+                self.restore_search_origin();
+                self.handle_action(Action::EnterMode(Mode::Normal))?;
+            }
+            KeyCode::Enter => {
+                let command = std::mem::take(&mut self.command_line);
+                if let Some(history) = self.history_mut() {
+                    if !command.is_empty() && history.last() != Some(&command) {
+                        history.push(command.clone());
+                    }
+                }
+                self.restore_search_origin();
+                let action = match self.mode {
+                    Mode::Search => Some(Action::Search(command)),
+                    Mode::SaveAs if command.is_empty() => None,
+                    Mode::SaveAs => Some(Action::SaveAs(command, false)),
+                    _ => Some(Action::ExecuteCommand(command)),
+                };
+                self.handle_action(Action::EnterMode(Mode::Normal))?;
+                if let Some(action) = action {
+                    self.handle_action(action)?;
+                }
+            }
+            KeyCode::Tab => self.complete_command_line(),
+            KeyCode::Up | KeyCode::Down => {
+                let up = key.code == KeyCode::Up;
+                let index = self.history_index;
+                let Some(history) = self.history_mut() else {
+                    return Ok(());
+                };
+
+                let index = match (index, up) {
+                    (None, true) => history.len().checked_sub(1),
+                    (Some(i), true) => Some(i.saturating_sub(1)),
+                    (Some(i), false) if i + 1 < history.len() => Some(i + 1),
+                    _ => None,
+                };
+                let line = index.map(|i| history[i].clone()).unwrap_or_default();
+                self.history_index = index;
+                self.command_line = line;
+                self.preview_search();
+            }
+            KeyCode::Backspace => {
+                self.command_line.pop();
+                self.preview_search();
+            }
+            KeyCode::Char(c) => {
+                self.command_line.push(c);
+                self.preview_search();
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn complete_command_line(&mut self) {
+        if let Some((prefix, candidates, index)) = &mut self.completion {
+            *index = (*index + 1) % candidates.len();
+            self.command_line = format!("{}{}", prefix, candidates[*index]);
+            return;
+        }
+
+        let (prefix, partial) = match self.mode {
+            Mode::SaveAs => ("", self.command_line.as_str()),
+            Mode::Command => match self.command_line.split_once(' ') {
+                Some((
+                    command @ ("e" | "edit" | "e!" | "edit!" | "w" | "write" | "w!" | "write!"
+                    | "r" | "read" | "so" | "source"),
+                    partial,
+                )) => (command, partial),
+                _ => return,
+            },
+            _ => return,
+        };
+        let prefix = if prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{} ", prefix)
+        };
+
+        let candidates = command::complete_path(partial);
+        match candidates.len() {
+            0 => self.set_status_message("No matching files".to_string()),
+            1 => self.command_line = format!("{}{}", prefix, candidates[0]),
+            _ => {
+                self.command_line = format!("{}{}", prefix, candidates[0]);
+                self.set_status_message(candidates.join("  "));
+                self.completion = Some((prefix, candidates, 0));
+            }
+        }
+    }
+
+    fn history_mut(&mut self) -> Option<&mut Vec<String>> {
+        match self.mode {
+            Mode::Command => Some(&mut self.command_history),
+            Mode::Search => Some(&mut self.search_history),
+            _ => None,
+        }
+    }
+
+    /// Runs `action` at every cursor when it's one that extra cursors follow;
+    /// anything else drops back to the single main cursor.
+    fn handle_action(&mut self, action: Action) -> Result<()> {
+        if self.extra_cursors.is_empty() {
+            return self.apply_action(action);
+        }
+        if !follows_cursors(&action) {
+            if !matches!(action, Action::AddCursor(_)) {
+                self.extra_cursors.clear();
+            }
+            return self.apply_action(action);
+        }
+
+        // Cursors sit on separate lines and these actions never add or
+        // remove lines, so one cursor's edit can't shift another.
+        let mut cursors = std::mem::take(&mut self.extra_cursors);
+        let main = (self.cx, self.cy);
+        for cursor in &mut cursors {
+            (self.cx, self.cy) = *cursor;
+            self.apply_action(action.clone())?;
+            *cursor = (self.cx.min(self.max_cx()), self.cy);
+        }
+        (self.cx, self.cy) = main;
+        let result = self.apply_action(action);
+        self.extra_cursors = cursors;
+        result
+    }
+
+    fn apply_action(&mut self, action: Action) -> Result<()> {
+        if !matches!(
+            action,
+            Action::MoveUp | Action::MoveDown | Action::PageUp | Action::PageDown
+        ) {
+            self.desired_cx = None;
+        }
+        let jump = matches!(
+            action,
+            Action::Search(_)
+                | Action::SearchNext
+                | Action::SearchPrevious
+                | Action::JumpToMark(..)
+                | Action::MatchBracket
+                | Action::GoToPercent(_)
+                | Action::GoToLine(_)
+        );
+        if jump {
+            self.push_jump();
+        }
+
+        match action {
+            Action::Nop => {}
+            Action::Quit => {
+                self.exit = true;
+            }
+            Action::Save(_) if self.current_buffer().file.is_none() => {
+                self.command_line.clear();
+                self.mode = Mode::SaveAs;
+            }
+            Action::Save(force) => {
+                let fix_end_of_line = self.fix_end_of_line;
+                let backup = self.backup;
+                let buffer = self.current_buffer_mut();
+                buffer.end_of_line |= fix_end_of_line;
+                match buffer.save(force, backup) {
+                    Ok(()) => self.set_status_message("File saved".to_string()),
+                    Err(e) => self.set_error_message(format!("{:#}", e)),
+                }
+            }
+            Action::SaveAs(path, force) => {
+                let fix_end_of_line = self.fix_end_of_line;
+                let backup = self.backup;
+                let buffer = self.current_buffer_mut();
+                buffer.end_of_line |= fix_end_of_line;
+                match buffer.save_as(path, force, backup) {
+                    Ok(()) => self.set_status_message("File saved as".to_string()),
+                    Err(e) => self.set_error_message(format!("{:#}", e)),
+                }
+            }
+            Action::OpenFile(path) => {
+                let path = PathBuf::from(path);
+                let existing = self
+                    .buffers
+                    .iter()
+                    .position(|b| b.file.as_deref().is_some_and(|f| same_file(f, &path)));
+
+                match existing {
+                    Some(index) => self.switch_buffer(index),
+                    None => match Buffer::from_file(&path) {
+                        Ok(buffer) => {
+                            self.buffers.push(buffer);
+                            self.switch_buffer(self.buffers.len() - 1);
+                        }
+                        Err(e) => self.set_error_message(format!("{:#}", e)),
+                    },
+                }
+            }
+            Action::Reload(force) => {
+                let Some(path) = self.current_buffer().file.clone() else {
+                    self.set_error_message("No file name".to_string());
+                    return Ok(());
+                };
+
+                if self.current_buffer().is_modified && !force {
+                    self.set_error_message(
+                        "No write since last change (add ! to override)".to_string(),
+                    );
+                } else {
+                    match Buffer::from_file(&path) {
+                        Ok(buffer) => {
+                            // Keep what was set on the buffer rather than read
+                            // from the file, like `-R` and marks.
+                            let old = std::mem::replace(self.current_buffer_mut(), buffer);
+                            let buffer = self.current_buffer_mut();
+                            buffer.read_only |= old.read_only;
+                            buffer.marks = old.marks;
+                            self.redraw = true;
+                            self.set_status_message(format!("Reloaded {}", path.display()));
+                        }
+                        Err(e) => self.set_error_message(format!("{:#}", e)),
+                    }
+                }
+            }
+            Action::MoveUp if self.wrap => self.move_visual_row(false),
+            Action::MoveDown if self.wrap => self.move_visual_row(true),
+            Action::MoveUp => {
+                if self.cy > 0 {
+                    self.move_to_line(self.cy - 1);
+                }
+            }
+            Action::MoveDown => {
+                if self.cy + 1 < self.current_buffer().len() {
+                    self.move_to_line(self.cy + 1);
+                }
+            }
+            Action::MoveLeft => {
+                let (cx, cy) = (self.cx, self.cy);
+                self.cx = self.current_buffer().char_start(cx, cy);
+            }
+            Action::MoveRight => {
+                let (cx, cy) = (self.cx, self.cy);
+                let next = self.current_buffer().char_end(cx, cy);
+                if next <= self.max_cx() {
+                    self.cx = next;
+                }
+            }
+            Action::MoveStartOfLine => {
+                self.cx = 0;
+            }
+            Action::MoveEndOfLine => {
+                self.cx = self.max_cx();
+                self.desired_cx = Some(usize::MAX);
+            }
+            // The view moves with the cursor, so paging scrolls a window at a
+            // time rather than recentering.
+            Action::PageUp => {
+                let y = self.cy.saturating_sub(self.visible_lines());
+                self.scroll_offset = self.scroll_offset.saturating_sub(self.cy - y);
+                self.move_to_line(y);
+            }
+            Action::PageDown => {
+                let last_line = self.current_buffer().len() - 1;
+                let y = (self.cy + self.visible_lines()).min(last_line);
+                self.scroll_offset += y - self.cy;
+                self.move_to_line(y);
+            }
+            Action::ScrollCursorTo(anchor) => self.scroll_cursor_to(anchor),
+            Action::ScrollDown => {
+                if self.scroll_offset + 1 < self.current_buffer().len() {
+                    self.scroll_offset += 1;
+                    let last = self.current_buffer().len() - 1;
+                    let top = self.scroll_offset.saturating_add(self.scroll_margin());
+                    self.cy = self.cy.max(top.min(last));
+                }
+            }
+            Action::ScrollUp => {
+                if self.scroll_offset > 0 {
+                    self.scroll_offset -= 1;
+                    while self.cy > self.scroll_offset && !self.margin_on_screen() {
+                        self.cy -= 1;
+                    }
+                }
+            }
+            Action::AddChar(c) => {
+                let cy = self.cy;
+                let cx = self.cx;
+                let line = self.current_buffer().get_line(cy);
+                let before = line.get(..cx).and_then(|text| text.chars().next_back());
+                let after = line.get(cx..).and_then(|text| text.chars().next());
+
+                if self.auto_pairs && after == Some(c) && closing_pair(c) {
+                    self.cx += c.len_utf8();
+                    return Ok(());
+                }
+
+                self.current_buffer_mut().insert_char(cx, cy, c)?;
+                self.cx += c.len_utf8();
+
+                if let Some(close) = opening_pair(c).filter(|_| self.auto_pairs) {
+                    let is_quote = c == close;
+                    if !is_quote || !before.is_some_and(|b| b.is_alphanumeric()) {
+                        let cx = self.cx;
+                        self.current_buffer_mut().insert_char(cx, cy, close)?;
+                    }
+                }
+            }
+            Action::ReplaceChar(c) => {
+                let cy = self.cy;
+                let cx = self.cx;
+                self.current_buffer_mut().replace_char(cx, cy, c)?;
+                self.cx += c.len_utf8();
+            }
+            Action::ReplaceUnderCursor(c) => {
+                let cy = self.cy;
+                let cx = self.cx;
+                if cx < self.current_buffer().get_line(cy).len() {
+                    self.current_buffer_mut().replace_char(cx, cy, c)?;
+                }
+            }
+            Action::NewLine => {
+                let cy = self.cy;
+                let cx = self.cx;
+                let auto_indent = self.auto_indent;
+                let new_cx = self.current_buffer_mut().insert_new_line(cy, cx, auto_indent)?;
+                self.cx = new_cx;
+                self.cy += 1;
+            }
+            Action::Append => {
+                let cy = self.cy;
+                self.cx = self.current_buffer().char_end(self.cx, cy);
+                self.mode = Mode::Insert;
+            }
+            Action::AppendEndOfLine => {
+                let cy = self.cy;
+                self.cx = self.current_buffer().get_line(cy).len();
+                self.mode = Mode::Insert;
+            }
+            Action::OpenLineBelow | Action::OpenLineAbove => {
+                let cy = self.cy;
+                let indent = if self.auto_indent {
+                    self.current_buffer().indentation(cy)
+                } else {
+                    String::new()
+                };
+                let y = if matches!(action, Action::OpenLineBelow) {
+                    cy + 1
+                } else {
+                    cy
+                };
+
+                self.cx = indent.len();
+                self.current_buffer_mut().insert_lines(y, vec![indent])?;
+                self.cy = y;
+                self.mode = Mode::Insert;
+            }
+            Action::DeleteChar => {
+                let cy = self.cy;
+                let cx = self.cx;
+                let start = self
+                    .current_buffer()
+                    .get_line(cy)
+                    .get(..cx)
+                    .and_then(|prefix| prefix.chars().next_back())
+                    .map(|c| cx - c.len_utf8());
+                if let Some(start) = start {
+                    self.current_buffer_mut().remove_char(start, cy)?;
+                    self.cx = start;
+                }
+            }
+            Action::Undo => self.undo(false),
+            Action::Redo => self.undo(true),
+            Action::InsertLiteral => self.literal = Some(String::new()),
+            Action::InsertDigraph => self.digraph = Some(None),
+            Action::DeleteWordBackward | Action::DeleteToLineStart => {
+                let (cx, cy) = (self.cx, self.cy);
+                let start = if matches!(action, Action::DeleteWordBackward) {
+                    self.current_buffer().previous_word_start(cx, cy)
+                } else {
+                    0
+                };
+                if start < cx {
+                    self.current_buffer_mut()
+                        .remove_range((start, cy), (cx, cy))?;
+                    self.cx = start;
+                }
+            }
+            Action::DeleteUnderCursor | Action::DeleteBeforeCursor => {
+                let cy = self.cy;
+                let cx = self.cx;
+                let line = self.current_buffer().get_line(cy).into_owned();
+                let (start, end) = if matches!(action, Action::DeleteUnderCursor) {
+                    (cx, self.current_buffer().char_end(cx, cy))
+                } else {
+                    let start = line[..cx]
+                        .chars()
+                        .next_back()
+                        .map_or(cx, |c| cx - c.len_utf8());
+                    (start, cx)
+                };
+
+                if start < end {
+                    let text = self
+                        .current_buffer_mut()
+                        .remove_range((start, cy), (end, cy))?;
+                    self.register = Some(RegisterContent::Chars(text));
+                    self.cx = start;
+                }
+            }
+            Action::DeleteLine => {
+                let cy = self.cy;
+                let line = self.current_buffer_mut().remove_line(cy)?;
+                self.register = Some(RegisterContent::Lines(vec![line]));
+
+                // Deleting the last line moves up; either way land on the
+                // first non-blank character like vim.
+                let cy = cy.min(self.current_buffer().len() - 1);
+                self.cy = cy;
+                self.cx = self.current_buffer().indentation(cy).len();
+            }
+            Action::Operate(op, motion) => {
+                let cy = self.cy;
+                let cx = self.cx;
+                let line_len = self.current_buffer().get_line(cy).len();
+
+                if motion == Motion::Line && op == Operator::Delete {
+                    return self.handle_action(Action::DeleteLine);
+                }
+
+                let (start, end) = match motion {
+                    Motion::Line => (0, line_len),
+                    Motion::WordEnd(count) => {
+                        let buffer = self.current_buffer();
+                        let start = (1..count).fold(cx, |x, _| buffer.next_word_start(x, cy));
+                        (cx, buffer.word_end(start, cy))
+                    }
+                    Motion::WordForward => (cx, self.current_buffer().next_word_start(cx, cy)),
+                    Motion::EndOfLine => (cx, line_len),
+                    Motion::StartOfLine => (0, cx),
+                    Motion::LinesDown(count) => {
+                        let end = (cy + count + 1).min(self.current_buffer().len());
+                        return self.operate_lines(op, cy..end);
+                    }
+                    Motion::LinesUp(count) => {
+                        return self.operate_lines(op, cy.saturating_sub(count)..cy + 1);
+                    }
+                };
+                let text = self
+                    .current_buffer_mut()
+                    .remove_range((start, cy), (end, cy))?;
+                self.register = Some(if motion == Motion::Line {
+                    RegisterContent::Lines(vec![text])
+                } else {
+                    RegisterContent::Chars(text)
+                });
+                self.cx = start;
+
+                if op == Operator::Change {
+                    self.mode = Mode::Insert;
                 }
             }
-            Action::MoveDown => {
-                if self.cy < self.current_buffer().len() as u16 - 1 {
+            Action::FindChar(find, c) => {
+                self.last_find = Some((find, c));
+                self.find_char(find, c, false);
+            }
+            Action::RepeatFind(reverse) => {
+                if let Some((find, c)) = self.last_find {
+                    let find = if reverse { find.reversed() } else { find };
+                    self.find_char(find, c, true);
+                }
+            }
+            Action::RepeatChange => self.repeat_change(None)?,
+            Action::SetMark(c) => {
+                if c.is_ascii_lowercase() {
+                    let position = (self.cx, self.cy);
+                    self.current_buffer_mut().marks.insert(c, position);
+                } else {
+                    self.set_error_message(format!("Invalid mark: {}", c));
+                }
+            }
+            Action::JumpToMark(c, exact) => match self.current_buffer().marks.get(&c).copied() {
+                Some((x, y)) => {
+                    let y = y.min(self.current_buffer().len() - 1);
+                    self.cy = y;
+                    self.cx = if exact {
+                        x
+                    } else {
+                        self.current_buffer().indentation(y).len()
+                    };
+                }
+                None => self.set_error_message(format!("Mark not set: {}", c)),
+            },
+            Action::MoveLineDown => {
+                let cy = self.cy;
+                if cy + 1 < self.current_buffer().len() {
+                    self.current_buffer_mut().swap_lines(cy, cy + 1)?;
                     self.cy += 1;
                 }
             }
-            Action::MoveLeft => {
-                if self.cx > 0 {
-                    self.cx -= 1;
+            Action::MoveLineUp => {
+                let cy = self.cy;
+                if cy > 0 {
+                    self.current_buffer_mut().swap_lines(cy, cy - 1)?;
+                    self.cy -= 1;
                 }
             }
-            Action::MoveRight => {
-                let line_len = self.current_buffer().get_line(self.cy as usize).len() as u16;
-                if self.cx < line_len {
-                    self.cx += 1;
+            Action::WordCount => {
+                let range = self.selection().map(|(start, end)| VisualRange {
+                    start,
+                    end,
+                    linewise: self.mode == Mode::VisualLine,
+                });
+                self.word_count(range);
+            }
+            Action::ToggleComment => {
+                let syntax = detect_syntax(&self.syntax_set, self.current_buffer());
+                let Some(token) = comment_token(&syntax.name) else {
+                    self.set_error_message(format!("No comment syntax for {}", syntax.name));
+                    return Ok(());
+                };
+
+                let cy = self.cy;
+                let line = self.current_buffer().get_line(cy).into_owned();
+                let indent = self.current_buffer().indentation(cy).len();
+                let rest = &line[indent..];
+                if rest.is_empty() {
+                    return Ok(());
+                }
+
+                let marker = token.trim_end();
+                if rest.starts_with(marker) {
+                    let len = if rest.starts_with(token) {
+                        token.len()
+                    } else {
+                        marker.len()
+                    };
+                    self.current_buffer_mut()
+                        .remove_range((indent, cy), (indent + len, cy))?;
+                    if self.cx >= indent {
+                        self.cx = self.cx.saturating_sub(len).max(indent);
+                    }
+                } else {
+                    self.current_buffer_mut().insert_str(indent, cy, token)?;
+                    if self.cx >= indent {
+                        self.cx += token.len();
+                    }
                 }
             }
-            Action::MoveStartOfLine => {
-                self.cx = 0;
+            Action::JumpBack => {
+                if self.jump_index == self.jumps.len() {
+                    self.push_jump();
+                    self.jump_index -= 1;
+                }
+                if self.jump_index > 0 {
+                    self.jump_index -= 1;
+                    self.go_to_jump(self.jumps[self.jump_index]);
+                }
             }
-            Action::MoveEndOfLine => {
-                let line_len = self.current_buffer().get_line(self.cy as usize).len() as u16;
-                self.cx = line_len;
+            Action::JumpForward => {
+                if self.jump_index + 1 < self.jumps.len() {
+                    self.jump_index += 1;
+                    self.go_to_jump(self.jumps[self.jump_index]);
+                }
             }
-            Action::PageUp => {
-                let visible_lines = self.visible_lines();
-                self.cy = self.cy.saturating_sub(visible_lines);
+            Action::GoToLine(line) => {
+                let len = self.current_buffer().len();
+                let cy = line.map_or(len, |line| line.clamp(1, len)) - 1;
+                self.cy = cy;
+                self.cx = self.current_buffer().indentation(cy).len();
             }
-            Action::PageDown => {
-                let visible_lines = self.visible_lines();
-                self.cy = (self.cy + visible_lines).min(self.current_buffer().len() as u16 - 1);
+            Action::GoToPercent(percent) => {
+                let len = self.current_buffer().len();
+                let line = (percent.min(100) * len).div_ceil(100).max(1);
+                let cy = line.min(len) - 1;
+                self.cy = cy;
+                self.cx = self.current_buffer().indentation(cy).len();
             }
-            Action::AddChar(c) => {
-                let cy = self.cy as usize;
-                let cx = self.cx as usize;
-                self.current_buffer_mut().insert_char(cx, cy, c)?;
-                self.cx += 1;
+            Action::MatchBracket => {
+                let (cx, cy) = (self.cx, self.cy);
+                if let Some((x, y)) = self.current_buffer().matching_bracket(cx, cy) {
+                    self.cx = x;
+                    self.cy = y;
+                }
             }
-            Action::NewLine => {
-                let cy = self.cy as usize;
-                let cx = self.cx as usize;
-                self.current_buffer_mut().insert_new_line(cy, cx);
-                self.cx = 0;
-                self.cy += 1;
+            Action::Paste => {
+                let cy = self.cy;
+                let cx = self.cx;
+                match self.register.clone() {
+                    Some(RegisterContent::Lines(lines)) => {
+                        self.current_buffer_mut().insert_lines(cy + 1, lines)?;
+                        self.cy += 1;
+                        self.cx = 0;
+                    }
+                    Some(RegisterContent::Chars(text)) => {
+                        // Like lines, characters go after the cursor.
+                        let cx = self.current_buffer().char_end(cx, cy);
+                        let (x, y) = self.current_buffer_mut().insert_str(cx, cy, &text)?;
+                        self.cx = self.current_buffer().char_start(x, y);
+                        self.cy = y;
+                    }
+                    None => {}
+                }
             }
-            Action::DeleteChar => {
-                let cy = self.cy as usize;
-                let cx = self.cx as usize;
-                self.current_buffer_mut().remove_char(cx, cy)?;
-                if cx > 0 {
-                    self.cx -= 1;
+            Action::PasteIndented => match self.register.clone() {
+                Some(RegisterContent::Lines(lines)) => {
+                    let cy = self.cy;
+                    let indent = self.current_buffer().indentation(cy);
+                    let lines = reindent(&lines, &indent, self.tab_width, self.expand_tab);
+                    self.current_buffer_mut().insert_lines(cy + 1, lines)?;
+                    self.cy += 1;
+                    self.cx = self.current_buffer().indentation(cy + 1).len();
                 }
+                _ => self.apply_action(Action::Paste)?,
+            },
+            Action::DeleteSelection => {
+                if let Some(((sx, sy), (ex, ey))) = self.selection() {
+                    let content = if self.mode == Mode::VisualLine {
+                        RegisterContent::Lines(self.current_buffer_mut().remove_lines(sy..ey + 1)?)
+                    } else {
+                        let end = self.current_buffer().char_end(ex, ey);
+                        RegisterContent::Chars(
+                            self.current_buffer_mut().remove_range((sx, sy), (end, ey))?,
+                        )
+                    };
+                    self.register = Some(content);
+                    self.cx = if self.mode == Mode::VisualLine { 0 } else { sx };
+                    self.cy = sy;
+                }
+                self.mode = Mode::Normal;
             }
-            Action::DeleteLine => {
-                let cy = self.cy as usize;
-                self.current_buffer_mut().remove_line(cy)?;
+            Action::ToggleCase => {
+                let (cx, cy) = (self.cx, self.cy);
+                let line = self.current_buffer().get_line(cy);
+                if let Some(c) = line.get(cx..).and_then(|rest| rest.chars().next()) {
+                    let toggled = toggle_case(c);
+                    self.current_buffer_mut().replace_char(cx, cy, toggled)?;
+                    self.cx = cx + toggled.len_utf8();
+                }
+            }
+            Action::ToggleCaseSelection => {
+                if let Some(((sx, sy), (ex, ey))) = self.selection() {
+                    let (start, end) = if self.mode == Mode::VisualLine {
+                        ((0, sy), (self.current_buffer().get_line(ey).len(), ey))
+                    } else {
+                        ((sx, sy), (self.current_buffer().char_end(ex, ey), ey))
+                    };
+                    let buffer = self.current_buffer_mut();
+                    let text: String = buffer
+                        .remove_range(start, end)?
+                        .chars()
+                        .map(toggle_case)
+                        .collect();
+                    buffer.insert_str(start.0, start.1, &text)?;
+                    self.cx = start.0;
+                    self.cy = sy;
+                }
+                self.mode = Mode::Normal;
+            }
+            Action::YankSelection => {
+                if let Some(((sx, sy), (ex, ey))) = self.selection() {
+                    let range = VisualRange {
+                        start: (sx, sy),
+                        end: (ex, ey),
+                        linewise: self.mode == Mode::VisualLine,
+                    };
+                    self.yank_flash = Some((range, Instant::now()));
+                    let content = if self.mode == Mode::VisualLine {
+                        RegisterContent::Lines(self.current_buffer().get_lines(sy..ey + 1))
+                    } else {
+                        let end = self.current_buffer().char_end(ex, ey);
+                        RegisterContent::Chars(self.current_buffer().text_range((sx, sy), (end, ey)))
+                    };
+                    self.register = Some(content);
+                    self.cx = sx;
+                    self.cy = sy;
+                }
+                self.mode = Mode::Normal;
             }
             Action::EnterMode(mode) => {
+                self.history_index = None;
+                if mode == Mode::Search {
+                    self.search_origin = Some((self.cx, self.cy, self.scroll_offset));
+                }
+                if mode == Mode::Command {
+                    self.visual_range = self.selection().map(|(start, end)| VisualRange {
+                        start,
+                        end,
+                        linewise: self.mode == Mode::VisualLine,
+                    });
+                    if self.visual_range.is_some() {
+                        self.command_line = "'<,'>".to_string();
+                    }
+                }
+                if matches!(mode, Mode::Visual | Mode::VisualLine)
+                    && !matches!(self.mode, Mode::Visual | Mode::VisualLine)
+                {
+                    self.anchor = (self.cx, self.cy);
+                }
                 self.mode = mode;
             }
+            Action::AddCursor(down) => {
+                let last = self
+                    .extra_cursors
+                    .iter()
+                    .map(|&(_, y)| y)
+                    .fold(
+                        self.cy,
+                        |last, y| if down { last.max(y) } else { last.min(y) },
+                    );
+                let y = if down {
+                    Some(last + 1).filter(|&y| y < self.current_buffer().len())
+                } else {
+                    last.checked_sub(1)
+                };
+                if let Some(y) = y {
+                    let col = self.cursor_column();
+                    let buffer = self.current_buffer();
+                    let y_len = buffer.get_line(y).len();
+                    let x = buffer
+                        .column_to_index(col, y, self.tab_width)
+                        .min(buffer.char_start(y_len, y));
+                    self.extra_cursors.push((x, y));
+                }
+            }
+            Action::ClearCursors => self.extra_cursors.clear(),
+            Action::SplitWindow => self.split_window(),
+            Action::CloseWindow => self.close_window(),
+            Action::OnlyWindow => {
+                self.windows = vec![self.current_window()];
+                self.active_window = 0;
+            }
+            Action::FocusWindow(index) => {
+                if index != self.active_window && index < self.windows.len() {
+                    self.swap_window(index);
+                    self.desired_cx = None;
+                }
+            }
             Action::NextBuffer => {
-                self.active_buffer = (self.active_buffer + 1) % self.buffers.len();
+                self.switch_buffer((self.active_buffer + 1) % self.buffers.len());
+            }
+            Action::NextModifiedBuffer(forward) => {
+                let len = self.buffers.len();
+                let next = (1..=len)
+                    .map(|offset| {
+                        if forward {
+                            (self.active_buffer + offset) % len
+                        } else {
+                            (self.active_buffer + len - offset) % len
+                        }
+                    })
+                    .find(|&i| self.buffers[i].is_modified);
+                match next {
+                    Some(index) => self.switch_buffer(index),
+                    None => self.set_error_message("No modified buffers".to_string()),
+                }
             }
             Action::PreviousBuffer => {
-                if self.active_buffer == 0 {
-                    self.active_buffer = self.buffers.len() - 1;
+                let len = self.buffers.len();
+                self.switch_buffer((self.active_buffer + len - 1) % len);
+            }
+            Action::CloseBuffer(force) => {
+                if self.current_buffer().is_modified && !force {
+                    self.set_error_message(
+                        "No write since last change (add ! to override)".to_string(),
+                    );
                 } else {
-                    self.active_buffer -= 1;
+                    let removed = self.active_buffer;
+                    self.buffers.remove(removed);
+                    self.remove_jumps(removed);
+                    if self.buffers.is_empty() {
+                        self.buffers.push(Buffer::new(None::<PathBuf>, ""));
+                    }
+                    let last = self.buffers.len() - 1;
+                    for window in &mut self.windows {
+                        if window.buffer > removed {
+                            window.buffer -= 1;
+                        } else if window.buffer == removed {
+                            let buffer = removed.min(last);
+                            let (cx, cy) = self.buffers[buffer].cursor;
+                            let (scroll_offset, left_col) = self.buffers[buffer].scroll;
+                            *window = Window {
+                                buffer,
+                                cx,
+                                cy,
+                                scroll_offset,
+                                left_col,
+                            };
+                        }
+                    }
+                    self.active_buffer = self.active_buffer.min(self.buffers.len() - 1);
+                    self.restore_view();
                 }
             }
             Action::ExecuteCommand(command) => {
-                self.handle_command(&command)?;
+                self.run_commands(&command)?;
+            }
+            Action::Search(query) => {
+                if !query.is_empty() {
+                    self.last_search = Some(query);
+                }
+                self.search(true);
+            }
+            Action::SearchNext => {
+                self.search(true);
+            }
+            Action::SearchPrevious => {
+                self.search(false);
             }
         }
         Ok(())
     }
 }
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn to_color(color: syntect_style::Color) -> Color {
+    Color::Rgb {
+        r: color.r,
+        g: color.g,
+        b: color.b,
+    }
+}
+
+/// The character a terminal sends for Ctrl plus `c`.
+fn control_char(c: char) -> char {
+    match c {
+        ' ' => '\0',
+        '4'..='7' => char::from(c as u8 - b'4' + 0x1c),
+        '?' => '\x7f',
+        '@'..='_' | 'a'..='z' => char::from(c.to_ascii_uppercase() as u8 & 0x1f),
+        _ => c,
+    }
+}
+
+fn expand_tabs(text: &str, col: &mut usize, tab_width: usize, list: bool) -> String {
+    let mut expanded = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == '\t' {
+            let width = tab_width - *col % tab_width;
+            if list {
+                expanded.push('→');
+                expanded.extend(std::iter::repeat_n(' ', width - 1));
+            } else {
+                expanded.extend(std::iter::repeat_n(' ', width));
+            }
+            *col += width;
+        } else if c.is_ascii_control() {
+            // Shown as the matching symbol from the Control Pictures block,
+            // so it stays one cell wide.
+            let picture = if c == '\x7f' {
+                0x2421
+            } else {
+                0x2400 + c as u32
+            };
+            expanded.push(char::from_u32(picture).unwrap_or(c));
+            *col += 1;
+        } else {
+            expanded.push(c);
+            *col += 1;
+        }
+    }
+    expanded
+}
+
+/// Shifts `lines` so the first non-blank one is indented like `indent`,
+/// keeping the indentation of the others relative to it.
+fn reindent(lines: &[String], indent: &str, tab_width: usize, expand_tab: bool) -> Vec<String> {
+    let width = |text: &str| {
+        text.chars().fold(0, |col, c| {
+            if c == '\t' {
+                col + tab_width - col % tab_width
+            } else {
+                col + 1
+            }
+        })
+    };
+    let leading = |line: &str| line.len() - line.trim_start_matches([' ', '\t']).len();
+
+    let Some(first) = lines.iter().find(|line| !line.trim().is_empty()) else {
+        return lines.to_vec();
+    };
+    let target = width(indent);
+    let current = width(&first[..leading(first)]);
+
+    lines
+        .iter()
+        .map(|line| {
+            if line.trim().is_empty() {
+                return line.clone();
+            }
+            let len = leading(line);
+            let col = (width(&line[..len]) + target).saturating_sub(current);
+            let indent = if expand_tab {
+                " ".repeat(col)
+            } else {
+                "\t".repeat(col / tab_width) + &" ".repeat(col % tab_width)
+            };
+            indent + &line[len..]
+        })
+        .collect()
+}
+
+/// Honours https://no-color.org and terminals that can't render color.
+fn color_enabled() -> bool {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    let dumb = std::env::var("TERM").is_ok_and(|term| term == "dumb");
+    !no_color && !dumb
+}
+
+fn overlay_segments<'a>(
+    ranges: &[(syntect_style::Style, &'a str)],
+    highlights: &[Range<usize>],
+) -> Vec<(syntect_style::Style, &'a str, bool)> {
+    let mut segments = Vec::new();
+    let mut offset = 0;
+
+    for &(style, text) in ranges {
+        let mut start = 0;
+        while start < text.len() {
+            let pos = offset + start;
+            let (end, highlighted) = match highlights.iter().find(|r| r.contains(&pos)) {
+                Some(r) => (r.end - offset, true),
+                None => {
+                    let next = highlights
+                        .iter()
+                        .map(|r| r.start)
+                        .filter(|&s| s > pos)
+                        .min()
+                        .unwrap_or(usize::MAX);
+                    (next.saturating_sub(offset), false)
+                }
+            };
+            let end = end.min(text.len());
+            segments.push((style, &text[start..end], highlighted));
+            start = end;
+        }
+        offset += text.len();
+    }
+
+    segments
+}
+
+/// Picks a syntax from the file name, then its extension, then a shebang or
+/// mode line at the top of the file.
+fn detect_syntax<'a>(syntax_set: &'a SyntaxSet, buffer: &Buffer) -> &'a SyntaxReference {
+    let path = buffer.file.as_deref();
+    let name = path
+        .and_then(Path::file_name)
+        .and_then(|name| name.to_str());
+    name.and_then(filename_syntax)
+        .and_then(|syntax| syntax_set.find_syntax_by_name(syntax))
+        // syntect lists some whole file names, like `Gemfile`, as extensions.
+        .or_else(|| name.and_then(|name| syntax_set.find_syntax_by_extension(name)))
+        .or_else(|| {
+            path.and_then(Path::extension)
+                .and_then(|extension| extension.to_str())
+                .and_then(|extension| syntax_set.find_syntax_by_extension(extension))
+        })
+        .or_else(|| syntax_set.find_syntax_by_first_line(&buffer.get_line(0)))
+        .unwrap_or_else(|| syntax_set.find_syntax_plain_text())
+}
+
+fn filename_syntax(name: &str) -> Option<&'static str> {
+    let syntax = match name {
+        "Makefile" | "makefile" | "GNUmakefile" | "Justfile" | "justfile" => "Makefile",
+        "Dockerfile" | "Containerfile" | "PKGBUILD" | "APKBUILD" | ".gitignore"
+        | ".dockerignore" | ".gitattributes" | ".env" | ".zshrc" | ".zprofile" => {
+            "Bourne Again Shell (bash)"
+        }
+        "Rakefile" | "Gemfile" | "Vagrantfile" | "Podfile" => "Ruby",
+        "SConstruct" | "SConscript" | "BUILD" | "WORKSPACE" => "Python",
+        _ => return None,
+    };
+    Some(syntax)
+}
+
+fn comment_token(syntax: &str) -> Option<&'static str> {
+    let token = match syntax {
+        "Rust" | "C" | "C++" | "C#" | "Go" | "Java" | "JavaScript" | "TypeScript" | "Scala"
+        | "D" | "Objective-C" | "Objective-C++" | "PHP" | "Groovy" => "// ",
+        "Python"
+        | "Ruby"
+        | "Perl"
+        | "R"
+        | "YAML"
+        | "Makefile"
+        | "TOML"
+        | "Bourne Again Shell (bash)"
+        | "Shell-Unix-Generic"
+        | "Tcl" => "# ",
+        "Lua" | "SQL" | "Haskell" => "-- ",
+        "LaTeX" | "TeX" | "Erlang" | "MATLAB" => "% ",
+        "Lisp" | "Clojure" => ";; ",
+        "Batch File" => "REM ",
+        _ => return None,
+    };
+    Some(token)
+}
+
+fn opening_pair(c: char) -> Option<char> {
+    match c {
+        '(' => Some(')'),
+        '[' => Some(']'),
+        '{' => Some('}'),
+        '"' | '\'' | '`' => Some(c),
+        _ => None,
+    }
+}
+
+fn closing_pair(c: char) -> bool {
+    matches!(c, ')' | ']' | '}' | '"' | '\'' | '`')
+}
+
+fn leading_number(line: &str) -> Option<i64> {
+    let start = line.find(|c: char| c.is_ascii_digit())?;
+    let end = line[start..]
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or(line.len(), |len| start + len);
+    let number: i64 = line[start..end].parse().unwrap_or(i64::MAX);
+    if line[..start].ends_with('-') {
+        Some(-number)
+    } else {
+        Some(number)
+    }
+}
+
+/// Actions that extra cursors repeat: typing, deleting and moving within a
+/// line, and switching between normal and insert mode.
+fn follows_cursors(action: &Action) -> bool {
+    matches!(
+        action,
+        Action::AddChar(_)
+            | Action::DeleteChar
+            | Action::DeleteUnderCursor
+            | Action::MoveLeft
+            | Action::MoveRight
+            | Action::MoveStartOfLine
+            | Action::MoveEndOfLine
+            | Action::Append
+            | Action::AppendEndOfLine
+            | Action::EnterMode(Mode::Insert | Mode::Normal)
+    )
+}
+
+fn toggle_case(c: char) -> char {
+    if c.is_uppercase() {
+        c.to_lowercase().next().unwrap_or(c)
+    } else {
+        c.to_uppercase().next().unwrap_or(c)
+    }
+}
+
+/// Whether `action` edits the buffer, so that `.` should repeat it.
+fn is_change(action: &Action) -> bool {
+    matches!(
+        action,
+        Action::EnterMode(Mode::Insert | Mode::Replace)
+            | Action::Append
+            | Action::AppendEndOfLine
+            | Action::OpenLineBelow
+            | Action::OpenLineAbove
+            | Action::Paste
+            | Action::PasteIndented
+            | Action::DeleteUnderCursor
+            | Action::DeleteBeforeCursor
+            | Action::DeleteLine
+            | Action::ToggleCase
+    )
+}
+
+/// Normal-mode commands that map a single key straight to an action. Keys
+/// that start a multi-key command and the motions shared with visual mode
+/// are handled in `handle_normal_key`.
+fn default_normal_keys() -> HashMap<Key, Action> {
+    let ctrl = |c| (KeyCode::Char(c), KeyModifiers::CONTROL);
+    let alt = |c| (KeyCode::Char(c), KeyModifiers::ALT);
+    let char = |c| (KeyCode::Char(c), KeyModifiers::NONE);
+    HashMap::from([
+        (ctrl('s'), Action::Save(false)),
+        (ctrl('n'), Action::NextBuffer),
+        (ctrl('p'), Action::PreviousBuffer),
+        (ctrl('o'), Action::JumpBack),
+        (ctrl('g'), Action::WordCount),
+        (alt('j'), Action::MoveLineDown),
+        (alt('k'), Action::MoveLineUp),
+        ((KeyCode::Tab, KeyModifiers::NONE), Action::JumpForward),
+        (char(':'), Action::EnterMode(Mode::Command)),
+        (char('/'), Action::EnterMode(Mode::Search)),
+        (char('i'), Action::EnterMode(Mode::Insert)),
+        (char('R'), Action::EnterMode(Mode::Replace)),
+        (char('v'), Action::EnterMode(Mode::Visual)),
+        (char('V'), Action::EnterMode(Mode::VisualLine)),
+        (char('a'), Action::Append),
+        (char('A'), Action::AppendEndOfLine),
+        (char('o'), Action::OpenLineBelow),
+        (char('O'), Action::OpenLineAbove),
+        (char('p'), Action::Paste),
+        (char('.'), Action::RepeatChange),
+        (char('x'), Action::DeleteUnderCursor),
+        (char('X'), Action::DeleteBeforeCursor),
+        (char('~'), Action::ToggleCase),
+        (char('u'), Action::Undo),
+        (ctrl('r'), Action::Redo),
+        (
+            (KeyCode::Down, KeyModifiers::CONTROL),
+            Action::AddCursor(true),
+        ),
+        (
+            (KeyCode::Up, KeyModifiers::CONTROL),
+            Action::AddCursor(false),
+        ),
+        ((KeyCode::Esc, KeyModifiers::NONE), Action::ClearCursors),
+    ])
+}
+
+fn default_insert_keys() -> HashMap<Key, Action> {
+    HashMap::from([
+        (
+            (KeyCode::Esc, KeyModifiers::NONE),
+            Action::EnterMode(Mode::Normal),
+        ),
+        ((KeyCode::Enter, KeyModifiers::NONE), Action::NewLine),
+        (
+            (KeyCode::Char('s'), KeyModifiers::CONTROL),
+            Action::Save(false),
+        ),
+        (
+            (KeyCode::Char('w'), KeyModifiers::CONTROL),
+            Action::DeleteWordBackward,
+        ),
+        (
+            (KeyCode::Char('u'), KeyModifiers::CONTROL),
+            Action::DeleteToLineStart,
+        ),
+        (
+            (KeyCode::Char('v'), KeyModifiers::CONTROL),
+            Action::InsertLiteral,
+        ),
+        (
+            (KeyCode::Char('k'), KeyModifiers::CONTROL),
+            Action::InsertDigraph,
+        ),
+    ])
+}
+
+/// Applies the bindings from the config on top of `keys`. Binding a key to
+/// `nop` turns it off; bad entries are reported through `errors`.
+fn build_keymap(
+    mut keys: HashMap<Key, Action>,
+    bindings: &HashMap<String, String>,
+    errors: &mut Vec<String>,
+) -> HashMap<Key, Action> {
+    for (key_name, action_name) in bindings {
+        let Some(key) = keymap::parse_key(key_name) else {
+            errors.push(format!("Unknown key in config: {}", key_name));
+            continue;
+        };
+        match named_action(action_name) {
+            Some(action) => {
+                keys.insert(key, action);
+            }
+            None => errors.push(format!("Unknown action in config: {}", action_name)),
+        }
+    }
+    keys
+}
+
+fn named_action(name: &str) -> Option<Action> {
+    let action = match name {
+        "nop" => Action::Nop,
+        "save" => Action::Save(false),
+        "quit" => Action::Quit,
+        "move_up" => Action::MoveUp,
+        "move_down" => Action::MoveDown,
+        "move_left" => Action::MoveLeft,
+        "move_right" => Action::MoveRight,
+        "move_start_of_line" => Action::MoveStartOfLine,
+        "move_end_of_line" => Action::MoveEndOfLine,
+        "page_up" => Action::PageUp,
+        "page_down" => Action::PageDown,
+        "scroll_up" => Action::ScrollUp,
+        "scroll_down" => Action::ScrollDown,
+        "new_line" => Action::NewLine,
+        "append" => Action::Append,
+        "append_end_of_line" => Action::AppendEndOfLine,
+        "open_line_below" => Action::OpenLineBelow,
+        "open_line_above" => Action::OpenLineAbove,
+        "delete_under_cursor" => Action::DeleteUnderCursor,
+        "delete_before_cursor" => Action::DeleteBeforeCursor,
+        "delete_word_backward" => Action::DeleteWordBackward,
+        "delete_to_line_start" => Action::DeleteToLineStart,
+        "insert_literal" => Action::InsertLiteral,
+        "insert_digraph" => Action::InsertDigraph,
+        "delete_line" => Action::DeleteLine,
+        "toggle_case" => Action::ToggleCase,
+        "undo" => Action::Undo,
+        "redo" => Action::Redo,
+        "add_cursor_below" => Action::AddCursor(true),
+        "add_cursor_above" => Action::AddCursor(false),
+        "clear_cursors" => Action::ClearCursors,
+        "paste" => Action::Paste,
+        "paste_indented" => Action::PasteIndented,
+        "repeat_change" => Action::RepeatChange,
+        "match_bracket" => Action::MatchBracket,
+        "jump_back" => Action::JumpBack,
+        "jump_forward" => Action::JumpForward,
+        "toggle_comment" => Action::ToggleComment,
+        "word_count" => Action::WordCount,
+        "move_line_down" => Action::MoveLineDown,
+        "move_line_up" => Action::MoveLineUp,
+        "next_buffer" => Action::NextBuffer,
+        "previous_buffer" => Action::PreviousBuffer,
+        "close_buffer" => Action::CloseBuffer(false),
+        "search_next" => Action::SearchNext,
+        "search_previous" => Action::SearchPrevious,
+        "split_window" => Action::SplitWindow,
+        "close_window" => Action::CloseWindow,
+        "only_window" => Action::OnlyWindow,
+        "normal_mode" => Action::EnterMode(Mode::Normal),
+        "insert_mode" => Action::EnterMode(Mode::Insert),
+        "replace_mode" => Action::EnterMode(Mode::Replace),
+        "visual_mode" => Action::EnterMode(Mode::Visual),
+        "visual_line_mode" => Action::EnterMode(Mode::VisualLine),
+        "command_mode" => Action::EnterMode(Mode::Command),
+        "search_mode" => Action::EnterMode(Mode::Search),
+        _ => return None,
+    };
+    Some(action)
+}
+
+fn motion_action(key: KeyEvent) -> Option<Action> {
+    match (key.code, key.modifiers) {
+        (KeyCode::Char('d'), KeyModifiers::CONTROL) => Some(Action::PageDown),
+        (KeyCode::Char('u'), KeyModifiers::CONTROL) => Some(Action::PageUp),
+        (KeyCode::Char('e'), KeyModifiers::CONTROL) => Some(Action::ScrollDown),
+        (KeyCode::Char('y'), KeyModifiers::CONTROL) => Some(Action::ScrollUp),
+        (_, KeyModifiers::CONTROL) => None,
+        (KeyCode::Up | KeyCode::Char('k'), _) => Some(Action::MoveUp),
+        (KeyCode::Down | KeyCode::Char('j'), _) => Some(Action::MoveDown),
+        (KeyCode::Left | KeyCode::Char('h'), _) => Some(Action::MoveLeft),
+        (KeyCode::Right | KeyCode::Char('l'), _) => Some(Action::MoveRight),
+        (KeyCode::Char('0'), _) => Some(Action::MoveStartOfLine),
+        (KeyCode::Char('$'), _) => Some(Action::MoveEndOfLine),
+        (KeyCode::Char('n'), _) => Some(Action::SearchNext),
+        (KeyCode::Char('N'), _) => Some(Action::SearchPrevious),
+        (KeyCode::Char(';'), _) => Some(Action::RepeatFind(false)),
+        (KeyCode::Char('%'), _) => Some(Action::MatchBracket),
+        (KeyCode::Char(','), _) => Some(Action::RepeatFind(true)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::tests::TempPath;
+
+    fn editor(text: &str) -> Editor {
+        let buffer = Buffer::new(None::<PathBuf>, text);
+        Editor::with_config(vec![buffer], (80, 24), Config::default()).unwrap()
+    }
+
+    /// Feeds `keys` to the editor, with `\x1b` for Escape, `\n` for Enter and
+    /// `\x08` for Backspace.
+    fn type_keys(editor: &mut Editor, keys: &str) {
+        for c in keys.chars() {
+            let code = match c {
+                '\x1b' => KeyCode::Esc,
+                '\n' => KeyCode::Enter,
+                '\x08' => KeyCode::Backspace,
+                c => KeyCode::Char(c),
+            };
+            editor
+                .handle_key(KeyEvent::new(code, KeyModifiers::NONE))
+                .unwrap();
+        }
+    }
+
+    fn text(editor: &Editor) -> String {
+        let buffer = editor.current_buffer();
+        (0..buffer.len())
+            .map(|y| buffer.get_line(y))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn inserts_typed_text() {
+        let mut editor = editor("\n");
+        type_keys(&mut editor, "ihello\x1b");
+        assert_eq!(text(&editor), "hello");
+        assert_eq!(editor.mode, Mode::Normal);
+    }
+
+    #[test]
+    fn splits_lines_on_enter() {
+        let mut editor = editor("onetwo\n");
+        type_keys(&mut editor, "3li\n\x1b");
+        assert_eq!(text(&editor), "one\ntwo");
+        assert_eq!((editor.cx, editor.cy), (0, 1));
+    }
+
+    #[test]
+    fn backspaces_over_a_multibyte_character() {
+        let mut editor = editor("aéc\n");
+        type_keys(&mut editor, "$i\x08b");
+        assert_eq!(text(&editor), "abc");
+        assert_eq!(editor.cx, 2);
+    }
+
+    #[test]
+    fn deletes_lines_with_a_count() {
+        let mut editor = editor("a\nb\nc\nd\n");
+        type_keys(&mut editor, "j2dd");
+        assert_eq!(text(&editor), "a\nd");
+        assert_eq!(editor.cy, 1);
+    }
+
+    #[test]
+    fn repeats_a_change_with_its_count() {
+        let mut editor = editor(&numbered_lines(8));
+        type_keys(&mut editor, "3dd.");
+        assert_eq!(editor.current_buffer().len(), 2);
+    }
+
+    #[test]
+    fn a_count_replaces_the_count_of_the_repeated_change() {
+        let mut editor = editor(&numbered_lines(8));
+        type_keys(&mut editor, "3dd2.");
+        assert_eq!(editor.current_buffer().len(), 3);
+        type_keys(&mut editor, ".");
+        assert_eq!(editor.current_buffer().len(), 1);
+    }
+
+    #[test]
+    fn deletes_a_word() {
+        let mut editor = editor("one two three\n");
+        type_keys(&mut editor, "dw");
+        assert_eq!(text(&editor), "two three");
+        assert_eq!(editor.cx, 0);
+    }
+
+    fn numbered_lines(count: usize) -> String {
+        (0..count).map(|i| format!("line {}\n", i)).collect()
+    }
+
+    #[test]
+    fn zero_moves_to_line_start_without_a_count() {
+        let mut editor = editor("  abc\n");
+        type_keys(&mut editor, "3l0");
+        assert_eq!(editor.cx, 0);
+    }
+
+    #[test]
+    fn zero_extends_a_count() {
+        let mut editor = editor(&numbered_lines(20));
+        type_keys(&mut editor, "10j");
+        assert_eq!((editor.cx, editor.cy), (0, 10));
+        type_keys(&mut editor, "3l0");
+        assert_eq!((editor.cx, editor.cy), (0, 10));
+    }
+
+    #[test]
+    fn goes_to_a_line_with_g() {
+        let mut editor = editor(&numbered_lines(20));
+        type_keys(&mut editor, "G");
+        assert_eq!(editor.cy, 19);
+        type_keys(&mut editor, "gg");
+        assert_eq!(editor.cy, 0);
+        type_keys(&mut editor, "5G");
+        assert_eq!(editor.cy, 4);
+        type_keys(&mut editor, "12gg");
+        assert_eq!(editor.cy, 11);
+    }
+
+    #[test]
+    fn centers_the_cursor_after_a_far_jump() {
+        let mut editor = editor(&numbered_lines(200));
+        let visible_lines = editor.visible_lines();
+        type_keys(&mut editor, "100G");
+        editor.draw(&mut Vec::new()).unwrap();
+        assert_eq!(editor.scroll_offset, 99 - visible_lines / 2);
+
+        // A short move past the edge only scrolls as far as it has to.
+        let bottom = editor.scroll_offset + visible_lines - 1;
+        editor.cy = bottom + 2;
+        editor.draw(&mut Vec::new()).unwrap();
+        assert_eq!(
+            editor.scroll_offset + visible_lines - 1,
+            bottom + 2 + editor.scroll_margin()
+        );
+    }
+
+    #[test]
+    fn deletes_lines_with_a_count_after_the_operator() {
+        let mut editor = editor(&numbered_lines(15));
+        type_keys(&mut editor, "d10j");
+        assert_eq!(editor.current_buffer().len(), 4);
+        assert_eq!(text(&editor).lines().next(), Some("line 11"));
+        assert_eq!(editor.cy, 0);
+    }
+
+    fn ctrl(editor: &mut Editor, c: char) {
+        editor
+            .handle_key(KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL))
+            .unwrap();
+    }
+
+    #[test]
+    fn undoes_a_whole_insert_at_once() {
+        let mut editor = editor("\n");
+        type_keys(&mut editor, "ione\x1botwo\x1b");
+        type_keys(&mut editor, "u");
+        assert_eq!(text(&editor), "one");
+        type_keys(&mut editor, "u");
+        assert_eq!(text(&editor), "");
+        ctrl(&mut editor, 'r');
+        assert_eq!(text(&editor), "one");
+        assert!(editor.current_buffer().is_modified);
+    }
+
+    #[test]
+    fn undoes_a_command_line_as_one_change() {
+        let mut editor = editor("b \na \n");
+        type_keys(&mut editor, ":sort | TrimWhitespace\n");
+        assert_eq!(text(&editor), "a\nb");
+        type_keys(&mut editor, "u");
+        assert_eq!(text(&editor), "b \na ");
+        assert!(!editor.current_buffer().is_modified);
+    }
+
+    #[test]
+    fn reports_when_there_is_nothing_to_undo_or_redo() {
+        let mut editor = editor("text\n");
+        type_keys(&mut editor, "u");
+        assert_eq!(
+            editor.status_message.as_ref().unwrap().0,
+            "Already at oldest change"
+        );
+        ctrl(&mut editor, 'r');
+        assert_eq!(
+            editor.status_message.as_ref().unwrap().0,
+            "Already at newest change"
+        );
+        assert_eq!(text(&editor), "text");
+    }
+
+    #[test]
+    fn runs_commands_after_a_substitution() {
+        let mut editor = editor("a a\n");
+        editor.buffers.push(Buffer::new(None::<PathBuf>, "other\n"));
+        type_keys(&mut editor, ":%s/a/x/g | bn\n");
+        assert_eq!(editor.active_buffer, 1);
+        assert_eq!(editor.buffers[0].get_line(0), "x x");
+    }
+
+    #[test]
+    fn undoes_a_change_after_switching_buffers() {
+        let mut editor = editor("b\na\n");
+        editor.buffers.push(Buffer::new(None::<PathBuf>, "other\n"));
+        type_keys(&mut editor, ":sort | bn\n");
+        assert_eq!(editor.active_buffer, 1);
+        type_keys(&mut editor, "u");
+        assert_eq!(text(&editor), "other");
+        type_keys(&mut editor, ":bp\nu");
+        assert_eq!(text(&editor), "b\na");
+    }
+
+    #[test]
+    fn highlights_only_brackets_matched_on_screen() {
+        let mut editor = editor(&format!("{{\n{}}}\n", numbered_lines(100)));
+        assert_eq!(editor.bracket_pair(), None);
+        type_keys(&mut editor, "%");
+        assert_eq!(editor.cy, 101);
+    }
+
+    #[test]
+    fn highlights_matching_brackets() {
+        let mut editor = editor("(\n)\n");
+        assert_eq!(editor.bracket_pair(), Some(((0, 0), (0, 1))));
+        type_keys(&mut editor, "%");
+        assert_eq!(editor.cy, 1);
+    }
+
+    #[test]
+    fn forgets_jumps_into_a_closed_buffer() {
+        let buffers = ["a\n", "b\n", "c\n"]
+            .iter()
+            .map(|text| Buffer::new(None::<PathBuf>, text))
+            .collect();
+        let mut editor = Editor::with_config(buffers, (80, 24), Config::default()).unwrap();
+        editor.jumps = vec![(0, 0, 0), (1, 0, 0), (2, 0, 0), (1, 0, 0)];
+        editor.jump_index = 3;
+        type_keys(&mut editor, ":bn\n:bd\n");
+        assert_eq!(editor.jumps, vec![(0, 0, 0), (1, 0, 0)]);
+        assert_eq!(editor.jump_index, 2);
+    }
+
+    #[test]
+    fn clamps_the_cursor_of_a_window_whose_buffer_shrank() {
+        let mut editor = editor(&numbered_lines(10));
+        type_keys(&mut editor, ":split\nG$");
+        ctrl(&mut editor, 'w');
+        type_keys(&mut editor, "jggd9j");
+        ctrl(&mut editor, 'w');
+        type_keys(&mut editor, "k");
+        assert_eq!((editor.cx, editor.cy, editor.scroll_offset), (0, 0, 0));
+        type_keys(&mut editor, "ix\x1b");
+        assert_eq!(text(&editor), "x");
+    }
+
+    #[test]
+    fn substitutes_the_last_search_as_searched() {
+        let mut editor = editor("bar b.r $1\n");
+        type_keys(&mut editor, "/b.r\n:s//x/g\n");
+        assert_eq!(text(&editor), "x x $1");
+    }
+
+    #[test]
+    fn substitutes_the_last_search_with_its_flags() {
+        let mut editor = editor("bar b.r Bar\n");
+        type_keys(&mut editor, ":set ic\n/\\Vb.r\n:s//$1/g\n");
+        assert_eq!(text(&editor), "bar $1 Bar");
+        type_keys(&mut editor, "/BAR\n:s//x/g\n");
+        assert_eq!(text(&editor), "x $1 x");
+    }
+
+    #[test]
+    fn substitutes_a_given_pattern_literally() {
+        let mut editor = editor("bar b.r\n");
+        type_keys(&mut editor, ":s/b.r/x/\n");
+        assert_eq!(text(&editor), "bar x");
+    }
+
+    #[test]
+    fn fits_the_status_line_on_a_narrow_screen() {
+        for width in [1, 10, 30] {
+            let buffer = Buffer::new(Some("a_rather_long_file_name.rs"), "text\n");
+            let mut editor =
+                Editor::with_config(vec![buffer], (width, 24), Config::default()).unwrap();
+            editor.set_status_message("a message that is longer than the screen".to_string());
+            editor.draw(&mut Vec::new()).unwrap();
+            let (status, right_status) = editor.drawn_status.clone().unwrap();
+            assert!(status.chars().count() + right_status.chars().count() <= width as usize);
+        }
+    }
+
+    #[test]
+    fn overwrites_a_session_only_with_a_bang() {
+        let path = TempPath::new("session");
+        std::fs::write(&path, "not a session").unwrap();
+        let mut editor = editor("\n");
+        type_keys(&mut editor, &format!(":mksession {}\n", path.display()));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a session");
+        type_keys(&mut editor, &format!(":mksession! {}\n", path.display()));
+        assert!(Session::load(&path).is_ok());
+    }
+
+    fn draw(editor: &mut Editor) {
+        editor.draw(&mut Vec::new()).unwrap();
+    }
+
+    #[test]
+    fn draws_after_leaving_insert_mode_past_a_multibyte_character() {
+        let mut editor = editor("\n");
+        type_keys(&mut editor, "i\u{e9}\x1b");
+        draw(&mut editor);
+    }
+
+    #[test]
+    fn draws_after_moving_to_a_multibyte_line_end() {
+        let mut editor = editor("caf\u{e9}\n");
+        type_keys(&mut editor, "$");
+        draw(&mut editor);
+    }
+
+    #[test]
+    fn draws_after_moving_right_over_a_multibyte_character() {
+        let mut editor = editor("a\u{e9}c\n");
+        type_keys(&mut editor, "l");
+        draw(&mut editor);
+        type_keys(&mut editor, "l");
+        draw(&mut editor);
+    }
+
+    #[test]
+    fn draws_after_pasting_text_that_ends_in_a_multibyte_character() {
+        let mut editor = editor("ab\n");
+        editor.register = Some(RegisterContent::Chars("x\u{e9}".to_string()));
+        type_keys(&mut editor, "p");
+        draw(&mut editor);
+        assert_eq!(text(&editor), "ax\u{e9}b");
+    }
+
+    #[test]
+    fn keeps_the_cursor_on_whole_characters() {
+        let mut editor = editor("a\u{e9}c caf\u{e9}\n");
+        type_keys(&mut editor, "l");
+        assert_eq!(editor.cx, 1);
+        type_keys(&mut editor, "l");
+        assert_eq!(editor.cx, 3);
+        type_keys(&mut editor, "h");
+        assert_eq!(editor.cx, 1);
+        type_keys(&mut editor, "$");
+        draw(&mut editor);
+        assert_eq!(editor.cx, 8);
+        type_keys(&mut editor, "x");
+        assert_eq!(text(&editor), "a\u{e9}c caf");
+    }
+
+    #[test]
+    fn steps_back_over_a_multibyte_character_when_leaving_insert_mode() {
+        let mut editor = editor("\n");
+        type_keys(&mut editor, "i\u{e9}\x1b");
+        draw(&mut editor);
+        assert_eq!(editor.cx, 0);
+    }
+
+    #[test]
+    fn puts_the_cursor_on_the_last_pasted_character() {
+        let mut editor = editor("ab\n");
+        editor.register = Some(RegisterContent::Chars("x\u{e9}".to_string()));
+        type_keys(&mut editor, "p");
+        assert_eq!(editor.cx, 2);
+    }
+
+    #[test]
+    fn inserts_a_digraph_and_leaves_insert_mode() {
+        let mut editor = editor("x\n");
+        type_keys(&mut editor, "A");
+        ctrl(&mut editor, 'k');
+        type_keys(&mut editor, "a:\x1b");
+        draw(&mut editor);
+        assert_eq!(text(&editor), "x\u{e4}");
+        assert_eq!((editor.mode, editor.cx), (Mode::Normal, 1));
+    }
+
+    #[test]
+    fn changes_words_with_a_count() {
+        for keys in ["2cwx\x1b", "c2wx\x1b"] {
+            let mut editor = editor("one two three\n");
+            type_keys(&mut editor, keys);
+            assert_eq!(text(&editor), "x three");
+        }
+    }
+
+    #[test]
+    fn changes_lines_with_a_count() {
+        let mut editor = editor("one\ntwo\nthree\n");
+        type_keys(&mut editor, "2ccx\x1b");
+        assert_eq!(text(&editor), "x\nthree");
+    }
+
+    #[test]
+    fn repeats_a_counted_change() {
+        let mut editor = editor("one two three four five\n");
+        type_keys(&mut editor, "2cwx\x1bl.");
+        assert_eq!(text(&editor), "x x five");
+    }
+
+    #[test]
+    fn moves_past_line_65536() {
+        let mut editor = editor(&numbered_lines(70_000));
+        type_keys(&mut editor, "G");
+        assert_eq!(editor.cy, 69_999);
+        type_keys(&mut editor, "65536Gj");
+        assert_eq!(editor.cy, 65_536);
+        ctrl(&mut editor, 'd');
+        assert!(editor.cy > 65_536);
+        draw(&mut editor);
+        assert!(editor.scroll_offset <= editor.cy);
+    }
+
+    #[test]
+    fn goes_to_a_percentage_past_line_65536() {
+        let mut editor = editor(&numbered_lines(70_000));
+        type_keys(&mut editor, "100%");
+        assert_eq!(editor.cy, 69_999);
+        type_keys(&mut editor, "95%");
+        assert_eq!(editor.cy, 66_499);
+    }
+
+    #[test]
+    fn keeps_read_only_and_marks_on_reload() {
+        let path = TempPath::new("reload");
+        std::fs::write(&path, "one\ntwo\n").unwrap();
+        let mut buffer = Buffer::from_file(&path).unwrap();
+        buffer.read_only = true;
+        let mut editor = Editor::with_config(vec![buffer], (80, 24), Config::default()).unwrap();
+        type_keys(&mut editor, "jma");
+        std::fs::write(&path, "one\nthree\n").unwrap();
+        type_keys(&mut editor, ":e!\n");
+        assert_eq!(text(&editor), "one\nthree");
+        assert!(editor.current_buffer().read_only);
+        assert_eq!(editor.current_buffer().marks.get(&'a'), Some(&(0, 1)));
+    }
+
+    fn editor_with_keys(text: &str, keys: &[(&str, &str)]) -> Editor {
+        let mut config = Config::default();
+        for &(key, action) in keys {
+            config
+                .keys
+                .normal
+                .insert(key.to_string(), action.to_string());
+        }
+        let buffer = Buffer::new(None::<PathBuf>, text);
+        Editor::with_config(vec![buffer], (80, 24), config).unwrap()
+    }
+
+    #[test]
+    fn turns_off_built_in_keys_bound_to_nop() {
+        let mut editor = editor_with_keys("abcd\n", &[("h", "nop"), ("d", "nop"), ("G", "nop")]);
+        type_keys(&mut editor, "llhGddx");
+        assert_eq!(editor.cx, 2);
+        assert_eq!(text(&editor), "abd");
+    }
+
+    #[test]
+    fn repeats_an_edit_bound_to_another_key() {
+        let mut editor = editor_with_keys("abcd\n", &[("s", "delete_under_cursor")]);
+        type_keys(&mut editor, "s.");
+        assert_eq!(text(&editor), "cd");
+    }
+
+    #[test]
+    fn moves_over_wrapped_rows_and_scrolls_by_them() {
+        let buffer = Buffer::new(None::<PathBuf>, &format!("{}\n", "a".repeat(50)).repeat(4));
+        let mut editor = Editor::with_config(vec![buffer], (20, 5), Config::default()).unwrap();
+        type_keys(&mut editor, ":set wrap\n");
+        type_keys(&mut editor, "j");
+        assert_eq!((editor.cx, editor.cy), (20, 0));
+        type_keys(&mut editor, "jj");
+        assert_eq!((editor.cx, editor.cy), (0, 1));
+        type_keys(&mut editor, "jjjj");
+        draw(&mut editor);
+        assert_eq!((editor.cx, editor.cy), (20, 2));
+        assert!(editor.scroll_offset > 0);
+        assert!(editor.cursor_row() < editor.visible_lines());
+        type_keys(&mut editor, "k");
+        assert_eq!((editor.cx, editor.cy), (0, 2));
+    }
+
+    #[test]
+    fn confirms_each_substitution() {
+        let mut editor = editor("a a a\na a\n");
+        type_keys(&mut editor, ":%s/a/b/gc\n");
+        type_keys(&mut editor, "yny");
+        assert_eq!(text(&editor), "b a b\na a");
+        type_keys(&mut editor, "q");
+        assert_eq!(text(&editor), "b a b\na a");
+        assert!(matches!(editor.mode, Mode::Normal));
+
+        type_keys(&mut editor, "u:%s/a/b/gc\n");
+        type_keys(&mut editor, "na");
+        assert_eq!(text(&editor), "a b b\nb b");
+        assert!(matches!(editor.mode, Mode::Normal));
+    }
+
+    #[test]
+    fn edits_at_every_cursor() {
+        let mut editor = editor("one\ntwo\nthree\n");
+        type_keys(&mut editor, "l");
+        for _ in 0..2 {
+            editor
+                .handle_key(KeyEvent::new(KeyCode::Down, KeyModifiers::CONTROL))
+                .unwrap();
+        }
+        type_keys(&mut editor, "ix\x1b");
+        assert_eq!(text(&editor), "oxne\ntxwo\ntxhree");
+        type_keys(&mut editor, "i\x08\x1b");
+        assert_eq!(text(&editor), "one\ntwo\nthree");
+        type_keys(&mut editor, "x");
+        assert_eq!(text(&editor), "oe\nto\ntree");
+        type_keys(&mut editor, "\x1bx");
+        assert!(editor.extra_cursors.is_empty());
+        assert_eq!(text(&editor), "o\nto\ntree");
+    }
+
+    #[test]
+    fn pairs_brackets_and_quotes() {
+        let mut editor = editor("\n");
+        type_keys(&mut editor, ":set autopairs\n");
+        type_keys(&mut editor, "if(a)\x1b");
+        assert_eq!(text(&editor), "f(a)");
+        type_keys(&mut editor, "A \"b\" it\"s\x1b");
+        assert_eq!(text(&editor), "f(a) \"b\" it\"s");
+        type_keys(&mut editor, ":set noautopairs\n");
+        type_keys(&mut editor, "A(\x1b");
+        assert_eq!(text(&editor), "f(a) \"b\" it\"s(");
+    }
+}
@@ -0,0 +1,102 @@
+use std::ops::Range;
+use syntect::{
+    highlighting::{HighlightIterator, HighlightState, Highlighter, Style, Theme},
+    parsing::{ParseState, ScopeStack, SyntaxReference, SyntaxSet},
+};
+
+use crate::buffer::Buffer;
+
+/// How many lines to keep before starting over from the top of the view.
+const MAX_LINES: usize = 5000;
+
+/// Highlighted lines, so redraws only run syntect on lines that changed or
+/// haven't been seen yet.
+///
+/// The cache covers a run of lines starting at `start`, which is highlighted
+/// as if the file began there. Each entry keeps the text it was made from, so
+/// an edit drops that line and everything after it.
+#[derive(Default)]
+pub struct HighlightCache {
+    syntax: String,
+    start: usize,
+    lines: Vec<CachedLine>,
+}
+
+struct CachedLine {
+    text: String,
+    spans: Vec<(Style, Range<usize>)>,
+    parse_state: ParseState,
+    highlight_state: HighlightState,
+}
+
+impl HighlightCache {
+    /// Prepares the cache for drawing `lines` of `buffer`.
+    pub fn begin(&mut self, syntax: &SyntaxReference, buffer: &Buffer, lines: Range<usize>) {
+        let cached_end = self.start + self.lines.len();
+        if self.syntax != syntax.name
+            || lines.start < self.start
+            || lines.start > cached_end
+            || lines.end > self.start + MAX_LINES
+        {
+            self.syntax = syntax.name.clone();
+            self.start = lines.start;
+            self.lines.clear();
+            return;
+        }
+
+        let end = lines.end.min(cached_end);
+        let stale =
+            (self.start..end).find(|&y| buffer.get_line(y) != self.lines[y - self.start].text);
+        if let Some(y) = stale {
+            self.lines.truncate(y - self.start);
+        }
+    }
+
+    /// The highlighted spans of line `y`, as byte ranges into the line.
+    /// Lines have to be asked for in order within the range given to `begin`.
+    pub fn line(
+        &mut self,
+        y: usize,
+        buffer: &Buffer,
+        syntax: &SyntaxReference,
+        syntax_set: &SyntaxSet,
+        theme: &Theme,
+    ) -> &[(Style, Range<usize>)] {
+        let highlighter = Highlighter::new(theme);
+        while self.start + self.lines.len() <= y {
+            let (mut parse_state, mut highlight_state) = match self.lines.last() {
+                Some(line) => (line.parse_state.clone(), line.highlight_state.clone()),
+                None => (
+                    ParseState::new(syntax),
+                    HighlightState::new(&highlighter, ScopeStack::new()),
+                ),
+            };
+
+            let text = buffer.get_line(self.start + self.lines.len()).into_owned();
+            // The default syntaxes expect every line to end in a newline.
+            let line = format!("{}\n", text);
+            let spans = match parse_state.parse_line(&line, syntax_set) {
+                Ok(ops) => {
+                    let mut offset = 0;
+                    HighlightIterator::new(&mut highlight_state, &ops, &line, &highlighter)
+                        .filter_map(|(style, piece)| {
+                            let range = offset..(offset + piece.len()).min(text.len());
+                            offset += piece.len();
+                            (!range.is_empty()).then_some((style, range))
+                        })
+                        .collect()
+                }
+                // Draw the line unstyled rather than print over the screen.
+                Err(_) => vec![(Style::default(), 0..text.len())],
+            };
+
+            self.lines.push(CachedLine {
+                text,
+                spans,
+                parse_state,
+                highlight_state,
+            });
+        }
+        &self.lines[y - self.start].spans
+    }
+}
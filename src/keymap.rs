@@ -0,0 +1,58 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+pub type Key = (KeyCode, KeyModifiers);
+
+/// The lookup key for an event. Shift is already part of a typed character,
+/// so it is dropped to make `A` match however the terminal reports it.
+pub fn key_of(event: KeyEvent) -> Key {
+    match event.code {
+        KeyCode::Char(_) => (event.code, event.modifiers - KeyModifiers::SHIFT),
+        code => (code, event.modifiers),
+    }
+}
+
+/// Parses a key such as `x`, `ctrl-s`, `alt-j` or `esc` as written in the
+/// config file.
+pub fn parse_key(text: &str) -> Option<Key> {
+    let mut modifiers = KeyModifiers::NONE;
+    let mut rest = text;
+    loop {
+        let lower = rest.to_ascii_lowercase();
+        let (modifier, len) = if lower.starts_with("ctrl-") {
+            (KeyModifiers::CONTROL, 5)
+        } else if lower.starts_with("alt-") {
+            (KeyModifiers::ALT, 4)
+        } else {
+            break;
+        };
+        // A lone "-" after the prefix is the minus key, not another modifier.
+        if rest.len() == len {
+            break;
+        }
+        modifiers |= modifier;
+        rest = &rest[len..];
+    }
+
+    let code = match rest.to_ascii_lowercase().as_str() {
+        "esc" => KeyCode::Esc,
+        "enter" => KeyCode::Enter,
+        "tab" => KeyCode::Tab,
+        "backspace" => KeyCode::Backspace,
+        "space" => KeyCode::Char(' '),
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        _ => {
+            let mut chars = rest.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if modifiers.contains(KeyModifiers::CONTROL) => {
+                    KeyCode::Char(c.to_ascii_lowercase())
+                }
+                (Some(c), None) => KeyCode::Char(c),
+                _ => return None,
+            }
+        }
+    };
+    Some((code, modifiers))
+}
@@ -1,23 +1,124 @@
 mod buffer;
+mod command;
+mod config;
+mod digraph;
 mod editor;
+mod highlight;
+mod keymap;
+mod register;
+mod screen;
+mod session;
 
 use buffer::Buffer;
 use editor::Editor;
+use screen::Terminal;
+use session::Session;
+use std::io::{IsTerminal, Read};
+use std::path::{Path, PathBuf};
 
 fn main() -> anyhow::Result<()> {
-    let files = std::env::args();
+    install_panic_hook();
+
+    let mut files = Vec::new();
+    let mut session = None;
+    let mut line = None;
+    let mut read_only = false;
+    let mut args = std::env::args().skip(1).peekable();
+    while let Some(arg) = args.next() {
+        if arg == "-S" {
+            let path = match args.peek() {
+                Some(path) if !path.starts_with('-') => args.next(),
+                _ => None,
+            };
+            session = Some(path.unwrap_or_else(|| session::DEFAULT_PATH.to_string()));
+        } else if arg == "-R" {
+            read_only = true;
+        } else if let Some(number) = arg.strip_prefix('+') {
+            // A bare `+` opens at the last line.
+            line = Some(number.parse::<usize>().unwrap_or(usize::MAX));
+        } else {
+            let (file, position) = split_position(&arg);
+            let cursor = position.or(line.take().map(|line| (0, line)));
+            files.push((file, cursor));
+        }
+    }
+
+    let _terminal = Terminal::enter()?;
     let mut buffers: Vec<Buffer> = Vec::new();
+    let mut active = 0;
+    if let Some(path) = session {
+        let session = Session::load(path)?;
+        for entry in session.buffers {
+            let mut buffer = Buffer::from_file(entry.path)?;
+            buffer.cursor = entry.cursor;
+            buffers.push(buffer);
+        }
+        active = session.active;
+    }
 
-    if files.len() < 2 {
-        let buffer = Buffer::new(Some("Untitled"), "\n");
-        buffers.push(buffer);
-    } else {
-        for file in files.skip(1) {
-            let buffer = Buffer::from_file(file)?;
+    if !files.is_empty() {
+        for (file, cursor) in files {
+            let mut buffer = Buffer::from_file(file)?;
+            if let Some((column, line)) = cursor {
+                buffer.cursor = (column.saturating_sub(1), line.saturating_sub(1));
+            }
             buffers.push(buffer);
         }
+    } else if buffers.is_empty() {
+        let stdin = std::io::stdin();
+        let mut contents = b"\n".to_vec();
+        if !stdin.is_terminal() {
+            contents.clear();
+            stdin.lock().read_to_end(&mut contents)?;
+        }
+        let mut buffer = Buffer::new(None::<PathBuf>, &String::from_utf8_lossy(&contents));
+        buffer.binary = std::str::from_utf8(&contents).is_err();
+        buffers.push(buffer);
     }
 
-    let mut editor = Editor::new(buffers)?;
+    for buffer in &mut buffers {
+        buffer.read_only |= read_only;
+    }
+
+    let mut editor = Editor::new(buffers, crossterm::terminal::size()?)?;
+    editor.focus_buffer(active);
     editor.run()
-}
\ No newline at end of file
+}
+
+/// Splits a `file:line` or `file:line:column` argument, as printed by
+/// compilers and grep, unless a file by the full name exists.
+fn split_position(arg: &str) -> (String, Option<(usize, usize)>) {
+    if Path::new(arg).exists() {
+        return (arg.to_string(), None);
+    }
+
+    let mut file = arg;
+    let mut numbers = Vec::new();
+    while numbers.len() < 2 {
+        let Some((rest, number)) = file.rsplit_once(':') else {
+            break;
+        };
+        match number.parse::<usize>() {
+            Ok(number) if !rest.is_empty() => {
+                numbers.push(number);
+                file = rest;
+            }
+            _ => break,
+        }
+    }
+    match numbers[..] {
+        [line] => (file.to_string(), Some((1, line))),
+        [column, line] => (file.to_string(), Some((column, line))),
+        _ => (arg.to_string(), None),
+    }
+}
+
+/// Restores the terminal before the panic message is printed, so a crash
+/// doesn't leave the shell in raw mode on the alternate screen.
+fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        screen::restore_terminal();
+        default_hook(info);
+    }));
+}
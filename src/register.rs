@@ -0,0 +1,5 @@
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegisterContent {
+    Lines(Vec<String>),
+    Chars(String),
+}
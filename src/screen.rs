@@ -0,0 +1,183 @@
+use crossterm::{
+    cursor,
+    style::{Attribute, Color, Print, SetAttribute, SetBackgroundColor, SetForegroundColor},
+    terminal::{self, ClearType},
+    ExecutableCommand, QueueableCommand,
+};
+use std::fs::File;
+use std::io::{IsTerminal, Write};
+
+/// Keeps the terminal in raw mode on the alternate screen while it's alive.
+pub struct Terminal;
+
+impl Terminal {
+    pub fn enter() -> std::io::Result<Self> {
+        // Keys are read from the controlling terminal even when stdin is a
+        // pipe, but the screen has to be one.
+        let has_tty = std::io::stdin().is_terminal() || File::open("/dev/tty").is_ok();
+        if !std::io::stdout().is_terminal() || !has_tty {
+            return Err(std::io::Error::other(
+                "ziv requires an interactive terminal",
+            ));
+        }
+
+        // Raw mode also turns off XON/XOFF flow control, so Ctrl-S reaches
+        // the editor instead of pausing the terminal.
+        terminal::enable_raw_mode()?;
+        std::io::stdout()
+            .execute(terminal::EnterAlternateScreen)?
+            .execute(terminal::Clear(ClearType::All))?
+            .execute(cursor::Show)?;
+        Ok(Terminal)
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        restore_terminal();
+    }
+}
+
+/// Leaves the alternate screen and raw mode. Safe to call more than once.
+pub fn restore_terminal() {
+    let mut stdout = std::io::stdout();
+    let _ = stdout.execute(cursor::Show);
+    let _ = stdout.execute(terminal::LeaveAlternateScreen);
+    let _ = terminal::disable_raw_mode();
+    let _ = stdout.flush();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Style {
+    pub fg: Color,
+    pub bg: Color,
+    pub bold: bool,
+    pub reverse: bool,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Self {
+            fg: Color::Reset,
+            bg: Color::Reset,
+            bold: false,
+            reverse: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Cell {
+    ch: char,
+    style: Style,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Self {
+            ch: ' ',
+            style: Style::default(),
+        }
+    }
+}
+
+pub struct Screen {
+    width: u16,
+    height: u16,
+    cells: Vec<Cell>,
+    previous: Option<Vec<Cell>>,
+}
+
+impl Screen {
+    pub fn new((width, height): (u16, u16)) -> Self {
+        Self {
+            width,
+            height,
+            cells: vec![Cell::default(); width as usize * height as usize],
+            previous: None,
+        }
+    }
+
+    pub fn resize(&mut self, size: (u16, u16)) {
+        *self = Self::new(size);
+    }
+
+    pub fn clear_row(&mut self, y: u16) {
+        if y >= self.height {
+            return;
+        }
+
+        let start = y as usize * self.width as usize;
+        self.cells[start..start + self.width as usize].fill(Cell::default());
+    }
+
+    pub fn put_str(&mut self, x: u16, y: u16, text: &str, style: Style) -> u16 {
+        let mut x = x;
+        for ch in text.chars() {
+            if x >= self.width || y >= self.height {
+                break;
+            }
+            self.put_char(x, y, ch, style);
+            x += 1;
+        }
+        x
+    }
+
+    pub fn put_char(&mut self, x: u16, y: u16, ch: char, style: Style) {
+        if x < self.width && y < self.height {
+            self.cells[y as usize * self.width as usize + x as usize] = Cell { ch, style };
+        }
+    }
+
+    pub fn set_background(&mut self, x: u16, y: u16, bg: Color) {
+        if x < self.width && y < self.height {
+            self.cells[y as usize * self.width as usize + x as usize]
+                .style
+                .bg = bg;
+        }
+    }
+
+    pub fn flush(&mut self, out: &mut impl Write) -> std::io::Result<()> {
+        if self.previous.is_none() {
+            out.queue(terminal::Clear(ClearType::All))?;
+        }
+
+        let mut position = None;
+        let mut current_style = None;
+        for (i, cell) in self.cells.iter().enumerate() {
+            if self.previous.as_ref().and_then(|p| p.get(i)) == Some(cell) {
+                continue;
+            }
+
+            let x = (i % self.width as usize) as u16;
+            let y = (i / self.width as usize) as u16;
+            if position != Some((x, y)) {
+                out.queue(cursor::MoveTo(x, y))?;
+            }
+            if current_style != Some(cell.style) {
+                queue_style(out, cell.style)?;
+                current_style = Some(cell.style);
+            }
+
+            out.queue(Print(cell.ch))?;
+            position = Some((x + 1, y));
+        }
+
+        out.queue(SetAttribute(Attribute::Reset))?;
+        self.previous = Some(self.cells.clone());
+        out.flush()
+    }
+}
+
+fn queue_style(out: &mut impl Write, style: Style) -> std::io::Result<()> {
+    out.queue(SetAttribute(Attribute::Reset))?
+        .queue(SetForegroundColor(style.fg))?
+        .queue(SetBackgroundColor(style.bg))?;
+    if style.bold {
+        out.queue(SetAttribute(Attribute::Bold))?;
+    }
+    if style.reverse {
+        out.queue(SetAttribute(Attribute::Reverse))?;
+    }
+    Ok(())
+}
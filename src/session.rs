@@ -0,0 +1,37 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_PATH: &str = "Session.toml";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub active: usize,
+    pub buffers: Vec<SessionBuffer>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionBuffer {
+    pub path: PathBuf,
+    pub cursor: (usize, usize),
+}
+
+impl Session {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read session: {:?}", path))?;
+        toml::from_str(&contents).with_context(|| format!("Failed to parse session: {:?}", path))
+    }
+
+    /// Writes the session to `path`, which must not exist yet unless `force`.
+    pub fn save(&self, path: impl AsRef<Path>, force: bool) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        if !force && path.exists() {
+            return Err(anyhow::anyhow!("{:?} exists (add ! to override)", path));
+        }
+        let contents = toml::to_string(self)?;
+        std::fs::write(path, contents)
+            .with_context(|| format!("Failed to write session: {:?}", path))
+    }
+}